@@ -2,7 +2,7 @@ use bevy::app::AppExit;
 use bevy::prelude::*;
 use bevy_egui::egui::Id;
 use bevy_egui::{egui, EguiContexts};
-use incrustmental::prelude::{ProductConditionKind, Quantity};
+use incrustmental::prelude::{Condition, LoseReason, Outcome, ProductConditionKind, Quantity};
 
 use crate::resources::StateRes;
 
@@ -48,7 +48,7 @@ pub fn update(
 ) {
     state.update(time.delta());
 
-    if state.win() {
+    if state.finished() {
         next_state.set(AppState::EndGame);
     }
 }
@@ -61,8 +61,19 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
             .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
             .show(egui_ctx, |ui| {
                 ui.label(&format!("Money: ${:.2}", state.money()));
+                for currency in state.currencies().iter() {
+                    ui.label(&format!("{}: {:.2}", currency.name(), currency.amount()));
+                }
 
-                for material in state.materials().iter() {
+                if let Some(reward) = state.prestige_reward() {
+                    if state.can_prestige()
+                        && ui.button(format!("Prestige for {:.0}", reward)).clicked()
+                    {
+                        state.prestige();
+                    }
+                }
+
+                for (id, material) in state.materials().iter().enumerate() {
                     if !material.active() {
                         continue;
                     }
@@ -71,12 +82,12 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
                         "{}s: {}, price: ${:.2}",
                         material.name(),
                         material.count(),
-                        material.price()
+                        state.material_price(id)
                     ));
                 }
 
-                for product in state.products().iter() {
-                    let price = if let Some(price) = product.price() {
+                for (id, product) in state.products().iter().enumerate() {
+                    let price = if let Some(price) = state.sell_price(id) {
                         format!(" price: ${:.2},", price)
                     } else {
                         "".to_string()
@@ -88,26 +99,44 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
                         product.count(),
                         product.sold(),
                         price,
-                        product.interest() * 100.
+                        state.interest(id) * 100.
                     ));
                     ui.label(format!(
                         "{} recipe: {}",
                         product.name(),
                         product.recipe(&state)
                     ));
+
+                    if let Some(order) = state.orders(id).next() {
+                        ui.add(
+                            egui::ProgressBar::new(order.progress() as f32)
+                                .text(format!("{} queued", state.orders(id).count())),
+                        );
+                    }
                 }
 
                 for i in 0..state.materials().len() {
-                    if state.materials()[i].active() && state.materials()[i].price() > 0.0 {
-                        if ui
-                            .button(&format!(
-                                "Buy {}s",
-                                state.materials()[i].name().to_lowercase()
-                            ))
-                            .clicked()
-                        {
-                            state.buy_material(i, 1);
-                        }
+                    if state.materials()[i].active() && state.material_price(i) > 0.0 {
+                        ui.horizontal(|ui| {
+                            if ui
+                                .button(&format!(
+                                    "Buy {}s",
+                                    state.materials()[i].name().to_lowercase()
+                                ))
+                                .clicked()
+                            {
+                                state.buy_material(i, 1);
+                            }
+
+                            let material = &state.materials()[i];
+                            if material.count() > 0
+                                && ui
+                                    .button(format!("Sell for {:.2}", state.material_refund(i)))
+                                    .clicked()
+                            {
+                                state.sell_material(i, 1);
+                            }
+                        });
                     }
                 }
 
@@ -174,13 +203,34 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
     });
 
     egui::SidePanel::left(Id::new("left_panel")).show(egui_ctx, |ui| {
+        for id in 0..state.challenges().len() {
+            let challenge = &state.challenges()[id];
+            if state.challenge() == Some(id) {
+                ui.label(&format!("Challenge: {}", challenge.name()));
+                if ui.button("Abandon").clicked() {
+                    state.abandon_challenge();
+                }
+            } else if state.can_start_challenge(id)
+                && ui
+                    .button(format!("Start challenge {}", challenge.name()))
+                    .clicked()
+            {
+                state.start_challenge(id);
+            }
+        }
+
+        if let Some(stage) = state.current_stage() {
+            ui.label(&format!("Stage: {}", stage.name()));
+            ui.add(egui::ProgressBar::new(state.stage_progress() as f32));
+        }
+
         ui.label("Objectives");
         for obj in state.objective().win_condition() {
             match obj {
-                Quantity::Money(p) => {
+                Condition::Money(p) => {
                     ui.label(&format!("Have ${:.2}", p));
                 }
-                Quantity::Material(id, cnt) => {
+                Condition::Material(id, cnt) => {
                     ui.label(&format!(
                         "{} {}{}",
                         cnt,
@@ -188,7 +238,7 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
                         if *cnt > 1 { "s" } else { "" }
                     ));
                 }
-                Quantity::Product(id, cnt, p_cond) => {
+                Condition::Product(id, cnt, p_cond) => {
                     let obj_kind = match p_cond.unwrap_or(ProductConditionKind::Produced) {
                         ProductConditionKind::Count => "Have available",
                         ProductConditionKind::Sold => "Sell",
@@ -202,6 +252,9 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
                         if *cnt > 1 { "s" } else { "" }
                     ));
                 }
+                _ => {
+                    ui.label(obj.as_str(&state));
+                }
             }
             ui.separator();
         }
@@ -244,6 +297,9 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
                         Quantity::Money(p) => {
                             ui.label(&format!("${:.2}, ", p));
                         }
+                        Quantity::Currency(id, p) => {
+                            ui.label(&format!("{:.2} {}, ", p, state.currencies()[*id].name()));
+                        }
                         Quantity::Material(id, cnt) => {
                             ui.label(&format!(
                                 "{} {}{}",
@@ -282,20 +338,32 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
 
         for (i, perk) in state.perks().iter().enumerate() {
             if perk.unlocked() {
-                if perk.active() {
+                if perk.maxed() {
                     let _ = ui
-                        .button(&format!("{}", perk.name()))
+                        .button(&format!("{} (level {})", perk.name(), perk.level()))
                         .on_hover_text(perk.description());
                 } else {
-                    if ui.button(&format!("Buy perk {}", perk.name())).clicked() {
+                    let label = if perk.active() {
+                        format!("Upgrade perk {} (level {})", perk.name(), perk.level())
+                    } else {
+                        format!("Buy perk {}", perk.name())
+                    };
+                    if ui.button(&label).clicked() {
                         buy = Some(i);
                     }
                     ui.label("Price:");
-                    for price in perk.price().iter() {
+                    for price in perk.next_price().iter() {
                         match price {
                             Quantity::Money(p) => {
                                 ui.label(&format!("${:.2}, ", p));
                             }
+                            Quantity::Currency(id, p) => {
+                                ui.label(&format!(
+                                    "{:.2} {}, ",
+                                    p,
+                                    state.currencies()[*id].name()
+                                ));
+                            }
                             Quantity::Material(id, cnt) => {
                                 ui.label(&format!(
                                     "{} {}{}",
@@ -336,14 +404,24 @@ pub fn draw(mut state: ResMut<StateRes>, ctx: EguiContexts) {
     });
 }
 
-pub fn end_screen(mut exit: EventWriter<AppExit>, ctx: EguiContexts) {
+pub fn end_screen(state: Res<StateRes>, mut exit: EventWriter<AppExit>, ctx: EguiContexts) {
     let egui_ctx = ctx.ctx();
 
     egui::CentralPanel::default().show(egui_ctx, |_| {
         egui::Area::new(Id::from("main"))
             .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
             .show(egui_ctx, |ui| {
-                ui.label("You win!");
+                match state.outcome() {
+                    Outcome::Lost(LoseReason::Bankruptcy) => {
+                        ui.label("You went bankrupt!");
+                    }
+                    Outcome::Lost(LoseReason::Condition(id)) => {
+                        ui.label(&format!("You lose: {}", state.lose_conditions()[id].name()));
+                    }
+                    _ => {
+                        ui.label("You win!");
+                    }
+                }
                 if ui.button("Ok").clicked() {
                     exit.send(AppExit::default());
                 }
@@ -0,0 +1,674 @@
+use crate::incremental::{
+    AutomationKind, Comparison, Condition, PerkTarget, PostWin, ProductConditionKind, Quantity,
+    Stage, StageEffect, Stat, State,
+};
+use crate::types::{
+    AutomationId, BadgeId, ChallengeId, Count, LoseConditionId, PerkId, ProductId,
+    ProductMaterialId, StageId,
+};
+
+// The object a condition belongs to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConditionOwner {
+    Objective,
+    Badge(BadgeId),
+    Perk(PerkId),
+    Automation(AutomationId),
+    LoseCondition(LoseConditionId),
+    Stage(StageId),
+    Prestige,
+    Challenge(ChallengeId),
+}
+
+// Reason why a condition can never be met.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Unmeetable {
+    #[doc = "The condition refers to a product that can never be unlocked."]
+    UnreachableProduct(ProductId),
+
+    #[doc = "The condition refers to a material that can never be unlocked."]
+    UnreachableMaterial(ProductMaterialId),
+
+    #[doc = "The condition needs more of a material than its limit allows."]
+    AboveLimit(ProductMaterialId, Count),
+
+    #[doc = "The condition needs a product to be sold, but it has no price."]
+    NeverSold(ProductId),
+
+    #[doc = "The stat can never compare to the value as needed."]
+    OutOfRange(Stat, Comparison, f64),
+}
+
+// Issue found in a game definition by [`State::analyze`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    UnreachableProduct(ProductId),
+    UnreachableMaterial(ProductMaterialId),
+    UnmeetableCondition(ConditionOwner, Unmeetable),
+
+    #[doc = "The perk is not listed in the perks of any product."]
+    OrphanPerk(PerkId),
+
+    #[doc = "The perk's quantity doesn't refer to the product or any of its dependencies."]
+    PerkTargetMismatch(PerkId, ProductId),
+
+    #[doc = "Products that depend on each other in a cycle, in dependency order."]
+    CircularDependency(Vec<ProductId>),
+
+    #[doc = "Perks that require each other in a cycle, so none of them can ever unlock."]
+    CircularPerkRequirement(Vec<PerkId>),
+}
+
+struct Names<'a>(&'a State);
+
+impl Names<'_> {
+    fn product(&self, id: ProductId) -> String {
+        format!("product '{}'", self.0.products()[id].name())
+    }
+
+    fn material(&self, id: ProductMaterialId) -> String {
+        format!("material '{}'", self.0.materials()[id].name())
+    }
+
+    fn perk(&self, id: PerkId) -> String {
+        format!("perk '{}'", self.0.perks()[id].name())
+    }
+
+    fn owner(&self, owner: ConditionOwner) -> String {
+        match owner {
+            ConditionOwner::Objective => "objective".to_string(),
+            ConditionOwner::Badge(id) => format!("badge '{}'", self.0.badges()[id].name()),
+            ConditionOwner::Perk(id) => self.perk(id),
+            ConditionOwner::Automation(id) => {
+                format!("automation '{}'", self.0.automations()[id].name())
+            }
+            ConditionOwner::LoseCondition(id) => {
+                format!("lose condition '{}'", self.0.lose_conditions()[id].name())
+            }
+            ConditionOwner::Stage(id) => format!("stage '{}'", self.0.stages()[id].name()),
+            ConditionOwner::Prestige => "prestige".to_string(),
+            ConditionOwner::Challenge(id) => {
+                format!("challenge '{}'", self.0.challenges()[id].name())
+            }
+        }
+    }
+}
+
+impl Issue {
+    // Human readable description of the issue using the names of the objects.
+    pub fn describe(&self, state: &State) -> String {
+        let names = Names(state);
+
+        match self {
+            Issue::UnreachableProduct(id) => {
+                format!("{} can never be unlocked", names.product(*id))
+            }
+            Issue::UnreachableMaterial(id) => {
+                format!("{} can never be unlocked", names.material(*id))
+            }
+            Issue::UnmeetableCondition(owner, reason) => {
+                let reason = match reason {
+                    Unmeetable::UnreachableProduct(id) => {
+                        format!("{} can never be unlocked", names.product(*id))
+                    }
+                    Unmeetable::UnreachableMaterial(id) => {
+                        format!("{} can never be unlocked", names.material(*id))
+                    }
+                    Unmeetable::AboveLimit(id, limit) => {
+                        format!("{} is limited to {}", names.material(*id), limit)
+                    }
+                    Unmeetable::NeverSold(id) => {
+                        format!("{} has no price and is never sold", names.product(*id))
+                    }
+                    Unmeetable::OutOfRange(stat, cmp, value) => format!(
+                        "{} {} {} is impossible",
+                        stat.as_str(state),
+                        cmp.symbol(),
+                        value
+                    ),
+                };
+                format!(
+                    "condition of {} can never be met: {}",
+                    names.owner(*owner),
+                    reason
+                )
+            }
+            Issue::OrphanPerk(id) => {
+                format!("{} is not attached to any product", names.perk(*id))
+            }
+            Issue::PerkTargetMismatch(perk, product) => format!(
+                "{} doesn't affect anything in the recipe of {}",
+                names.perk(*perk),
+                names.product(*product)
+            ),
+            Issue::CircularDependency(ids) => format!(
+                "circular dependency between {}",
+                ids.iter()
+                    .map(|id| names.product(*id))
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ),
+            Issue::CircularPerkRequirement(ids) => format!(
+                "circular requirement between {}",
+                ids.iter()
+                    .map(|id| names.perk(*id))
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ),
+        }
+    }
+}
+
+struct Analyzer<'a> {
+    state: &'a State,
+    products: Vec<bool>,
+    materials: Vec<bool>,
+    upkeep: bool, // whether any stock has upkeep
+    issues: Vec<Issue>,
+}
+
+impl<'a> Analyzer<'a> {
+    fn new(state: &'a State) -> Self {
+        Self {
+            state,
+            products: state.products().iter().map(|p| p.active()).collect(),
+            materials: state.materials().iter().map(|m| m.active()).collect(),
+            upkeep: state.materials().iter().any(|m| m.upkeep() > 0.0)
+                || state.products().iter().any(|p| p.upkeep() > 0.0),
+            issues: Vec::new(),
+        }
+    }
+
+    // Mirrors the unlocking done during the game - unlocking a product
+    // unlocks all of its dependencies and byproducts and the products it unlocks
+    // may be unlocked later on. Automations may build or buy
+    // their target even if it's not unlocked. So may stages when completed.
+    fn reachability(&mut self) {
+        for automation in self.state.automations().iter() {
+            match automation.kind() {
+                AutomationKind::Build(id) => self.products[id] = true,
+                AutomationKind::Buy(id) => self.materials[id] = true,
+            }
+        }
+
+        for effect in self.state.stages().iter().flat_map(Stage::effects) {
+            match *effect {
+                StageEffect::UnlockProduct(id) => self.products[id] = true,
+                StageEffect::UnlockMaterial(id) => self.materials[id] = true,
+                _ => {}
+            }
+        }
+
+        let mut stack: Vec<_> = (0..self.products.len())
+            .filter(|id| self.products[*id])
+            .collect();
+
+        while let Some(id) = stack.pop() {
+            let product = &self.state.products()[id];
+
+            let deps = product
+                .dependencies()
+                .iter()
+                .map(|rel| *rel.quantity())
+                .chain(product.outputs().iter().copied())
+                .chain(product.random().rare_outputs().iter().map(|(q, _)| *q));
+            let unlocks = product.unlocks().iter().map(|(id, _)| *id);
+
+            for q in deps {
+                match q {
+                    Quantity::Material(id, _) => self.materials[id] = true,
+                    Quantity::Product(id, _, _) => {
+                        if !self.products[id] {
+                            self.products[id] = true;
+                            stack.push(id);
+                        }
+                    }
+                    Quantity::Money(_) | Quantity::Currency(_, _) => {}
+                }
+            }
+
+            for id in unlocks {
+                if !self.products[id] {
+                    self.products[id] = true;
+                    stack.push(id);
+                }
+            }
+        }
+
+        for (id, reachable) in self.products.iter().enumerate() {
+            if !reachable {
+                self.issues.push(Issue::UnreachableProduct(id));
+            }
+        }
+
+        for (id, reachable) in self.materials.iter().enumerate() {
+            if !reachable {
+                self.issues.push(Issue::UnreachableMaterial(id));
+            }
+        }
+    }
+
+    // Whether any perk modifies the limit of a material.
+    fn limit_perks(&self, id: ProductMaterialId) -> bool {
+        self.state.perks().iter().any(|perk| {
+            matches!(perk.perk().0, PerkTarget::MaterialLimit(material, _) if material == id)
+        })
+    }
+
+    // Range of values a stat may have during the game along
+    // with the reason the range is restricted, if any.
+    fn range(&self, stat: Stat) -> (f64, f64, Option<Unmeetable>) {
+        let state = self.state;
+        let constant = |reason| (state.stat(stat), state.stat(stat), Some(reason));
+
+        match stat {
+            Stat::Material(id) | Stat::MaterialBought(id) | Stat::MaterialDecayed(id)
+                if !self.materials[id] =>
+            {
+                constant(Unmeetable::UnreachableMaterial(id))
+            }
+            // Perks may raise the limit.
+            Stat::Material(id) if self.limit_perks(id) => (0.0, f64::INFINITY, None),
+            Stat::Material(id) => match state.materials()[id].limit() {
+                Some(limit) => (0.0, limit as f64, Some(Unmeetable::AboveLimit(id, limit))),
+                None => (0.0, f64::INFINITY, None),
+            },
+            Stat::Product(id, _) | Stat::ProductDecayed(id) if !self.products[id] => {
+                constant(Unmeetable::UnreachableProduct(id))
+            }
+            Stat::Product(id, ProductConditionKind::Sold)
+                if state.products()[id].price().is_none() =>
+            {
+                constant(Unmeetable::NeverSold(id))
+            }
+            Stat::Badge(_) | Stat::Perk(_) | Stat::Automation(_) | Stat::Challenge(_) => {
+                (0.0, 1.0, None)
+            }
+            Stat::Badges => (0.0, state.badges().len() as f64, None),
+            // Paying the upkeep may leave the player in debt.
+            Stat::Money if self.upkeep => (f64::NEG_INFINITY, f64::INFINITY, None),
+            _ => (0.0, f64::INFINITY, None),
+        }
+    }
+
+    // Checks whether a list of conditions joined with either
+    // `All` or `Any` may ever evaluate to `expected`.
+    fn satisfiable_list(
+        &self,
+        conds: &[Condition],
+        all: bool,
+        expected: bool,
+    ) -> Result<(), Unmeetable> {
+        // `All` needs every condition to be true in order to be true,
+        // while `Any` needs every condition to be false in order to be false.
+        if all == expected {
+            return conds.iter().try_for_each(|c| self.satisfiable(c, expected));
+        }
+
+        let mut reason = None;
+        for c in conds.iter() {
+            match self.satisfiable(c, expected) {
+                Ok(()) => return Ok(()),
+                Err(r) => {
+                    reason.get_or_insert(r);
+                }
+            }
+        }
+
+        reason.map_or(Ok(()), Err)
+    }
+
+    // Checks whether the condition may ever evaluate to `expected`.
+    fn satisfiable(&self, cond: &Condition, expected: bool) -> Result<(), Unmeetable> {
+        match cond {
+            Condition::All(conds) => self.satisfiable_list(conds, true, expected),
+            Condition::Any(conds) => self.satisfiable_list(conds, false, expected),
+            Condition::Not(conds) => self.satisfiable_list(conds, false, !expected),
+            Condition::Expr(_) => Ok(()),
+            _ => {
+                let (stat, cmp, value) = cond.comparison().unwrap();
+                let (lo, hi, reason) = self.range(stat);
+
+                let possible = match if expected { cmp } else { cmp.negate() } {
+                    Comparison::Lt => lo < value,
+                    Comparison::Le => lo <= value,
+                    Comparison::Eq => lo <= value && value <= hi,
+                    Comparison::Ne => lo < hi || lo != value,
+                    Comparison::Ge => hi >= value,
+                    Comparison::Gt => hi > value,
+                };
+
+                if possible {
+                    Ok(())
+                } else {
+                    Err(reason.unwrap_or(Unmeetable::OutOfRange(stat, cmp, value)))
+                }
+            }
+        }
+    }
+
+    fn conditions(&mut self, owner: ConditionOwner, conds: &[Condition]) {
+        for cond in conds.iter() {
+            if let Err(reason) = self.satisfiable(cond, true) {
+                self.issues.push(Issue::UnmeetableCondition(owner, reason));
+            }
+        }
+    }
+
+    fn all_conditions(&mut self) {
+        let state = self.state;
+
+        self.conditions(ConditionOwner::Objective, state.objective().win_condition());
+        if let PostWin::NewObjective(objective) = state.post_win() {
+            self.conditions(ConditionOwner::Objective, objective.win_condition());
+        }
+
+        for (id, badge) in state.badges().iter().enumerate() {
+            self.conditions(ConditionOwner::Badge(id), badge.condition());
+        }
+
+        for (id, perk) in state.perks().iter().enumerate() {
+            self.conditions(ConditionOwner::Perk(id), perk.condition());
+        }
+
+        for (id, automation) in state.automations().iter().enumerate() {
+            self.conditions(ConditionOwner::Automation(id), automation.condition());
+        }
+
+        for (id, lose) in state.lose_conditions().iter().enumerate() {
+            self.conditions(ConditionOwner::LoseCondition(id), lose.condition());
+        }
+
+        for (id, stage) in state.stages().iter().enumerate() {
+            self.conditions(ConditionOwner::Stage(id), stage.objective().win_condition());
+        }
+
+        if let Some(prestige) = state.prestige_layer() {
+            self.conditions(ConditionOwner::Prestige, prestige.condition());
+        }
+
+        for (id, challenge) in state.challenges().iter().enumerate() {
+            self.conditions(
+                ConditionOwner::Challenge(id),
+                challenge.goal().win_condition(),
+            );
+        }
+    }
+
+    fn perks(&mut self) {
+        let state = self.state;
+
+        for perk_id in 0..state.perks().len() {
+            let (target, _) = state.perks()[perk_id].perk();
+            // Perks targeting materials and automations apply on their own.
+            let mut attached = matches!(
+                target,
+                PerkTarget::Regeneration(_, _)
+                    | PerkTarget::MaterialDecay(_, _)
+                    | PerkTarget::ProductDecay(_, _)
+                    | PerkTarget::MaterialLimit(_, _)
+                    | PerkTarget::MaterialGrowth(_, _)
+                    | PerkTarget::AutomationTimer(_, _)
+                    | PerkTarget::AutomationBatch(_, _)
+            );
+
+            for (product_id, product) in state.products().iter().enumerate() {
+                if !state.product_perks(product_id).contains(&perk_id) {
+                    continue;
+                }
+                attached = true;
+                // Scoped perks apply to groups of products, some of which may not have the target.
+                if !product.perks().contains(&perk_id) {
+                    continue;
+                }
+
+                let matches = match target {
+                    PerkTarget::Quantity(Quantity::Product(id, _, _)) if id == product_id => true,
+                    PerkTarget::Quantity(target) => product
+                        .dependencies()
+                        .iter()
+                        .map(|rel| rel.quantity())
+                        .chain(product.outputs().iter())
+                        .chain(product.random().rare_outputs().iter().map(|(q, _)| q))
+                        .any(|q| q.same_asset(&target)),
+                    PerkTarget::CritChance(_) => true,
+                    PerkTarget::SellPrice(_) => product.price().is_some(),
+                    PerkTarget::Interest(_) => product.price().is_some(),
+                    PerkTarget::Regeneration(_, _)
+                    | PerkTarget::MaterialDecay(_, _)
+                    | PerkTarget::ProductDecay(_, _)
+                    | PerkTarget::MaterialLimit(_, _)
+                    | PerkTarget::MaterialGrowth(_, _)
+                    | PerkTarget::AutomationTimer(_, _)
+                    | PerkTarget::AutomationBatch(_, _) => false,
+                };
+
+                if !matches {
+                    self.issues
+                        .push(Issue::PerkTargetMismatch(perk_id, product_id));
+                }
+            }
+
+            if !attached {
+                self.issues.push(Issue::OrphanPerk(perk_id));
+            }
+        }
+    }
+
+    // Reports every cycle in the product dependencies and the perk prerequisites once.
+    fn cycles(&mut self) {
+        let state = self.state;
+
+        let products = find_cycles(state.products().len(), |id| {
+            state.products()[id]
+                .dependencies()
+                .iter()
+                .filter_map(|rel| match rel.quantity() {
+                    Quantity::Product(dep, _, _) => Some(*dep),
+                    _ => None,
+                })
+                .collect()
+        });
+        self.issues
+            .extend(products.into_iter().map(Issue::CircularDependency));
+
+        let perks = find_cycles(state.perks().len(), |id| {
+            state.perks()[id].requires().to_vec()
+        });
+        self.issues
+            .extend(perks.into_iter().map(Issue::CircularPerkRequirement));
+    }
+}
+
+// Depth-first search over a graph of `len` nodes, where `edges`
+// returns the nodes a node points to, returning every cycle found once.
+fn find_cycles(len: usize, edges: impl Fn(usize) -> Vec<usize>) -> Vec<Vec<usize>> {
+    #[derive(Copy, Clone, PartialEq)]
+    enum Mark {
+        New,
+        Visiting,
+        Done,
+    }
+
+    fn visit(
+        id: usize,
+        edges: &dyn Fn(usize) -> Vec<usize>,
+        marks: &mut [Mark],
+        path: &mut Vec<usize>,
+        cycles: &mut Vec<Vec<usize>>,
+    ) {
+        marks[id] = Mark::Visiting;
+        path.push(id);
+
+        for dep in edges(id) {
+            match marks[dep] {
+                Mark::New => visit(dep, edges, marks, path, cycles),
+                Mark::Visiting => {
+                    let start = path.iter().position(|p| *p == dep).unwrap();
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(dep);
+                    cycles.push(cycle);
+                }
+                Mark::Done => {}
+            }
+        }
+
+        path.pop();
+        marks[id] = Mark::Done;
+    }
+
+    let mut marks = vec![Mark::New; len];
+    let mut cycles = Vec::new();
+    for id in 0..len {
+        if marks[id] == Mark::New {
+            visit(id, &edges, &mut marks, &mut Vec::new(), &mut cycles);
+        }
+    }
+
+    cycles
+}
+
+impl State {
+    // Statically analyzes the game definition without simulating it
+    // and reports issues like products that can never be unlocked,
+    // conditions that can never be met or misconfigured perks.
+    pub fn analyze(&self) -> Vec<Issue> {
+        let mut analyzer = Analyzer::new(self);
+
+        analyzer.reachability();
+        analyzer.all_conditions();
+        analyzer.perks();
+        analyzer.cycles();
+
+        analyzer.issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use either::Either;
+
+    use super::{ConditionOwner, Issue, Unmeetable};
+    use crate::incremental::{
+        Comparison, Condition, Objective, Perk, PerkKind, Product, ProductMaterial, Quantity,
+        Relation, Stat, State,
+    };
+    use crate::loader::load;
+
+    #[test]
+    fn analyze_examples() {
+        for path in ["res/lemonstand.yml", "res/walking_sim.yml"] {
+            let state = load(Path::new(path)).unwrap();
+            assert_eq!(state.analyze(), vec![]);
+        }
+    }
+
+    #[test]
+    fn analyze_issues() {
+        let state = State::new(
+            0.0,
+            Objective::new(vec![Condition::Any(vec![
+                Condition::Material(0, 10),
+                Condition::Not(vec![Condition::Compare(Stat::Badges, Comparison::Ge, 0.0)]),
+            ])]),
+            vec![ProductMaterial::new(
+                0,
+                Some(5),
+                "shop".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )],
+            vec![
+                Product::new(
+                    "a".to_string(),
+                    None,
+                    vec![Relation::consumes(Quantity::Product(1, 1, None))],
+                    vec![0],
+                    vec![],
+                    true,
+                ),
+                Product::new(
+                    "b".to_string(),
+                    None,
+                    vec![Relation::consumes(Quantity::Product(0, 1, None))],
+                    vec![],
+                    vec![],
+                    false,
+                ),
+                Product::new("c".to_string(), None, vec![], vec![], vec![], false),
+            ],
+            vec![],
+            vec![
+                Perk::new(
+                    "wrong".to_string(),
+                    "".to_string(),
+                    vec![Condition::Product(2, 1, None)],
+                    vec![],
+                    (Quantity::Material(0, 1), PerkKind::Set),
+                ),
+                Perk::new(
+                    "orphan".to_string(),
+                    "".to_string(),
+                    vec![],
+                    vec![],
+                    (Quantity::Money(1.0), PerkKind::Set),
+                ),
+            ],
+            vec![],
+        );
+
+        let issues = state.analyze();
+        assert_eq!(
+            issues,
+            vec![
+                Issue::UnreachableProduct(2),
+                Issue::UnmeetableCondition(ConditionOwner::Objective, Unmeetable::AboveLimit(0, 5)),
+                Issue::UnmeetableCondition(
+                    ConditionOwner::Perk(0),
+                    Unmeetable::UnreachableProduct(2)
+                ),
+                Issue::PerkTargetMismatch(0, 0),
+                Issue::OrphanPerk(1),
+                Issue::CircularDependency(vec![0, 1, 0]),
+            ]
+        );
+        assert_eq!(
+            issues[0].describe(&state),
+            "product 'c' can never be unlocked"
+        );
+    }
+
+    #[test]
+    fn analyze_perk_cycles() {
+        let perk = |name: &str, requires| {
+            Perk::new(
+                name.to_string(),
+                "".to_string(),
+                vec![],
+                vec![],
+                (Quantity::Money(1.0), PerkKind::Add),
+            )
+            .with_requires(requires)
+        };
+        let state = State::new(
+            0.0,
+            Objective::new(vec![]),
+            vec![],
+            vec![],
+            vec![],
+            vec![perk("a", vec![1]), perk("b", vec![0]), perk("c", vec![0])],
+            vec![],
+        );
+
+        let issues = state.analyze();
+        let cycle = Issue::CircularPerkRequirement(vec![0, 1, 0]);
+        assert!(issues.contains(&cycle));
+        assert_eq!(
+            cycle.describe(&state),
+            "circular requirement between perk 'a' -> perk 'b' -> perk 'a'"
+        );
+    }
+}
@@ -0,0 +1,583 @@
+use std::fmt::Write;
+
+use derive_getters::Getters;
+
+use crate::incremental::{
+    AutomationKind, Condition, PerkScope, PerkTarget, PostWin, Quantity, StageEffect, Stat, State,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NodeKind {
+    Money,
+    Currency,
+    Objective,
+    Material,
+    Product,
+    Perk,
+    Automation,
+    Badge,
+    LoseCondition,
+    Stage,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EdgeKind {
+    #[doc = "Product depends on an asset. Labeled by the `RelationKind`."]
+    Relation,
+
+    #[doc = "Product is sold for, or material is bought with, a currency other than money."]
+    Currency,
+
+    #[doc = "Product yields a byproduct when constructed."]
+    Output,
+
+    #[doc = "Product unlocks another product, a stage unlocks a product or material,"]
+    #[doc = "or a perk is a parent of another perk in the perk tree."]
+    Unlocks,
+
+    #[doc = "Perk modifies a product's recipe, a material's regeneration or a stock's decay."]
+    Perk,
+
+    #[doc = "Automation builds a product or buys a material."]
+    Automates,
+
+    #[doc = "An asset is referenced by the condition of a perk, badge, automation,"]
+    #[doc = "lose condition or the objective, or a perk excludes another perk."]
+    Condition,
+}
+
+#[derive(Clone, Debug, Getters)]
+pub struct Node {
+    id: String,
+    #[getter(copy)]
+    kind: NodeKind,
+    label: String,
+}
+
+#[derive(Clone, Debug, Getters)]
+pub struct Edge {
+    from: String,
+    to: String,
+    #[getter(copy)]
+    kind: EdgeKind,
+    label: String,
+}
+
+// Graph of the game's economy - the materials, products, perks,
+// automations and badges along with the relations between them.
+// Can be rendered as Graphviz DOT or Mermaid flowchart so designers
+// can inspect a loaded definition.
+#[derive(Clone, Debug, Default, Getters)]
+pub struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+const MONEY_NODE: &str = "money";
+const OBJECTIVE_NODE: &str = "objective";
+
+fn currency_node(id: usize) -> String {
+    format!("c{}", id)
+}
+
+fn material_node(id: usize) -> String {
+    format!("m{}", id)
+}
+
+fn product_node(id: usize) -> String {
+    format!("p{}", id)
+}
+
+fn perk_node(id: usize) -> String {
+    format!("k{}", id)
+}
+
+fn automation_node(id: usize) -> String {
+    format!("a{}", id)
+}
+
+fn badge_node(id: usize) -> String {
+    format!("b{}", id)
+}
+
+fn lose_node(id: usize) -> String {
+    format!("l{}", id)
+}
+
+fn stage_node(id: usize) -> String {
+    format!("s{}", id)
+}
+
+fn quantity_node(q: &Quantity) -> String {
+    match q {
+        Quantity::Money(_) => MONEY_NODE.to_string(),
+        Quantity::Currency(id, _) => currency_node(*id),
+        Quantity::Material(id, _) => material_node(*id),
+        Quantity::Product(id, _, _) => product_node(*id),
+    }
+}
+
+fn stat_node(stat: &Stat) -> Option<String> {
+    match stat {
+        Stat::Money => Some(MONEY_NODE.to_string()),
+        Stat::Currency(id) => Some(currency_node(*id)),
+        Stat::Material(id)
+        | Stat::MaterialBought(id)
+        | Stat::MaterialDecayed(id)
+        | Stat::MaterialPrice(id)
+        | Stat::MaterialLimit(id) => Some(material_node(*id)),
+        Stat::Product(id, _)
+        | Stat::ProductDecayed(id)
+        | Stat::SellPrice(id)
+        | Stat::Interest(id) => Some(product_node(*id)),
+        Stat::Badge(id) => Some(badge_node(*id)),
+        Stat::Perk(id) => Some(perk_node(*id)),
+        Stat::Automation(id) | Stat::AutomationTimer(id) | Stat::AutomationBatch(id) => {
+            Some(automation_node(*id))
+        }
+        Stat::Badges | Stat::TimePlayed | Stat::Prestiges | Stat::Challenge(_) => None,
+    }
+}
+
+fn condition_label(cond: &Condition) -> String {
+    match cond {
+        Condition::Money(money) => quantity_label(&Quantity::Money(*money)),
+        Condition::Currency(id, amount) => quantity_label(&Quantity::Currency(*id, *amount)),
+        Condition::Material(id, cnt) => quantity_label(&Quantity::Material(*id, *cnt)),
+        Condition::Product(id, cnt, kind) => quantity_label(&Quantity::Product(*id, *cnt, *kind)),
+        _ => match cond.comparison() {
+            Some((Stat::Product(_, kind), cmp, value)) => {
+                format!("{:?} {} {}", kind, cmp.symbol(), value)
+            }
+            Some((Stat::MaterialBought(_), cmp, value)) => {
+                format!("bought {} {}", cmp.symbol(), value)
+            }
+            Some((Stat::MaterialDecayed(_) | Stat::ProductDecayed(_), cmp, value)) => {
+                format!("decayed {} {}", cmp.symbol(), value)
+            }
+            Some((_, cmp, value)) => format!("{} {}", cmp.symbol(), value),
+            None => String::new(),
+        },
+    }
+}
+
+fn quantity_label(q: &Quantity) -> String {
+    match q {
+        Quantity::Money(money) | Quantity::Currency(_, money) => format!("{:.2}", money),
+        Quantity::Material(_, cnt) => cnt.to_string(),
+        Quantity::Product(_, cnt, kind) => match kind {
+            Some(kind) => format!("{} {:?}", cnt, kind),
+            None => cnt.to_string(),
+        },
+    }
+}
+
+impl Graph {
+    pub fn new(state: &State) -> Self {
+        let mut graph = Graph::default();
+
+        graph.add_node(MONEY_NODE.to_string(), NodeKind::Money, "Money");
+        graph.add_node(OBJECTIVE_NODE.to_string(), NodeKind::Objective, "Objective");
+
+        for (id, c) in state.currencies().iter().enumerate() {
+            graph.add_node(currency_node(id), NodeKind::Currency, c.name());
+        }
+
+        for (id, m) in state.materials().iter().enumerate() {
+            graph.add_node(material_node(id), NodeKind::Material, m.name());
+
+            if let Some(currency) = m.currency() {
+                graph.add_edge(
+                    material_node(id),
+                    currency_node(currency),
+                    EdgeKind::Currency,
+                    "bought with".to_string(),
+                );
+            }
+        }
+
+        for (id, product) in state.products().iter().enumerate() {
+            graph.add_node(product_node(id), NodeKind::Product, product.name());
+
+            if let (Some(currency), Some(_)) = (product.currency(), product.price()) {
+                graph.add_edge(
+                    product_node(id),
+                    currency_node(currency),
+                    EdgeKind::Currency,
+                    "sold for".to_string(),
+                );
+            }
+
+            for rel in product.dependencies().iter() {
+                graph.add_edge(
+                    product_node(id),
+                    quantity_node(rel.quantity()),
+                    EdgeKind::Relation,
+                    format!("{:?} {}", rel.kind(), quantity_label(rel.quantity())),
+                );
+            }
+
+            for output in product.outputs().iter() {
+                graph.add_edge(
+                    product_node(id),
+                    quantity_node(output),
+                    EdgeKind::Output,
+                    format!("outputs {}", quantity_label(output)),
+                );
+            }
+
+            for (output, chance) in product.random().rare_outputs().iter() {
+                graph.add_edge(
+                    product_node(id),
+                    quantity_node(output),
+                    EdgeKind::Output,
+                    format!("outputs {} ({}%)", quantity_label(output), chance * 100.0),
+                );
+            }
+
+            for (unlock_id, cnt) in product.unlocks().iter() {
+                graph.add_edge(
+                    product_node(id),
+                    product_node(*unlock_id),
+                    EdgeKind::Unlocks,
+                    format!("unlocks at {}", cnt),
+                );
+            }
+
+            for perk_id in state.product_perks(id).iter() {
+                let (target, kind) = state.perks()[*perk_id].perk();
+                let target = match target {
+                    PerkTarget::Quantity(q) => quantity_label(&q),
+                    PerkTarget::CritChance(chance) => format!("crit chance {}", chance),
+                    PerkTarget::Regeneration(material, rate) => {
+                        format!(
+                            "{} regeneration {}",
+                            state.materials()[material].name(),
+                            rate
+                        )
+                    }
+                    PerkTarget::MaterialDecay(material, by) => {
+                        format!("{} decay {}", state.materials()[material].name(), by)
+                    }
+                    PerkTarget::ProductDecay(product, by) => {
+                        format!("{} decay {}", state.products()[product].name(), by)
+                    }
+                    PerkTarget::SellPrice(by) => format!("sell price {}", by),
+                    PerkTarget::Interest(by) => format!("interest {}", by),
+                    PerkTarget::MaterialLimit(material, by) => {
+                        format!("{} limit {}", state.materials()[material].name(), by)
+                    }
+                    PerkTarget::MaterialGrowth(material, by) => {
+                        format!("{} growth {}", state.materials()[material].name(), by)
+                    }
+                    PerkTarget::AutomationTimer(automation, by) => {
+                        format!("{} timer {}", state.automations()[automation].name(), by)
+                    }
+                    PerkTarget::AutomationBatch(automation, by) => {
+                        format!("{} batch {}", state.automations()[automation].name(), by)
+                    }
+                };
+                graph.add_edge(
+                    perk_node(*perk_id),
+                    product_node(id),
+                    EdgeKind::Perk,
+                    format!("{:?} {}", kind, target),
+                );
+            }
+        }
+
+        for (id, perk) in state.perks().iter().enumerate() {
+            graph.add_node(perk_node(id), NodeKind::Perk, perk.name());
+
+            let (target, kind) = perk.perk();
+            let modified = match target {
+                PerkTarget::Regeneration(material, rate) => {
+                    vec![(material_node(material), format!("regeneration {}", rate))]
+                }
+                PerkTarget::MaterialDecay(material, by) => {
+                    vec![(material_node(material), format!("decay {}", by))]
+                }
+                PerkTarget::ProductDecay(product, by) => {
+                    vec![(product_node(product), format!("decay {}", by))]
+                }
+                PerkTarget::MaterialLimit(material, by) => {
+                    vec![(material_node(material), format!("limit {}", by))]
+                }
+                PerkTarget::MaterialGrowth(material, by) => {
+                    vec![(material_node(material), format!("growth {}", by))]
+                }
+                PerkTarget::AutomationTimer(automation, by)
+                | PerkTarget::AutomationBatch(automation, by) => {
+                    let what = match target {
+                        PerkTarget::AutomationTimer(_, _) => "timer",
+                        _ => "batch",
+                    };
+                    // Perks scoped to all automations modify every one of them.
+                    let automations = match perk.scope() {
+                        PerkScope::Automations => (0..state.automations().len()).collect(),
+                        _ => vec![automation],
+                    };
+                    automations
+                        .into_iter()
+                        .map(|id| (automation_node(id), format!("{} {}", what, by)))
+                        .collect()
+                }
+                _ => Vec::new(),
+            };
+            for (to, label) in modified {
+                graph.add_edge(
+                    perk_node(id),
+                    to,
+                    EdgeKind::Perk,
+                    format!("{:?} {}", kind, label),
+                );
+            }
+            for parent in perk.requires().iter() {
+                graph.add_edge(
+                    perk_node(*parent),
+                    perk_node(id),
+                    EdgeKind::Unlocks,
+                    "unlocks".to_string(),
+                );
+            }
+            for other in perk.excludes().iter() {
+                graph.add_edge(
+                    perk_node(id),
+                    perk_node(*other),
+                    EdgeKind::Condition,
+                    "excludes".to_string(),
+                );
+            }
+            graph.add_conditions(perk_node(id), perk.condition());
+        }
+
+        for (id, automation) in state.automations().iter().enumerate() {
+            graph.add_node(automation_node(id), NodeKind::Automation, automation.name());
+
+            let (to, label) = match automation.kind() {
+                AutomationKind::Build(id) => (product_node(id), "builds"),
+                AutomationKind::Buy(id) => (material_node(id), "buys"),
+            };
+            graph.add_edge(
+                automation_node(id),
+                to,
+                EdgeKind::Automates,
+                label.to_string(),
+            );
+            graph.add_conditions(automation_node(id), automation.condition());
+        }
+
+        for (id, badge) in state.badges().iter().enumerate() {
+            graph.add_node(badge_node(id), NodeKind::Badge, badge.name());
+            graph.add_conditions(badge_node(id), badge.condition());
+        }
+
+        graph.add_conditions(
+            OBJECTIVE_NODE.to_string(),
+            state.objective().win_condition(),
+        );
+        if let PostWin::NewObjective(objective) = state.post_win() {
+            graph.add_conditions(OBJECTIVE_NODE.to_string(), objective.win_condition());
+        }
+
+        for (id, stage) in state.stages().iter().enumerate() {
+            graph.add_node(stage_node(id), NodeKind::Stage, stage.name());
+            graph.add_conditions(stage_node(id), stage.objective().win_condition());
+
+            for effect in stage.effects().iter() {
+                let to = match *effect {
+                    StageEffect::UnlockProduct(id) => product_node(id),
+                    StageEffect::UnlockMaterial(id) => material_node(id),
+                    _ => continue,
+                };
+                graph.add_edge(stage_node(id), to, EdgeKind::Unlocks, "unlocks".to_string());
+            }
+        }
+
+        for (id, lose) in state.lose_conditions().iter().enumerate() {
+            graph.add_node(lose_node(id), NodeKind::LoseCondition, lose.name());
+            graph.add_conditions(lose_node(id), lose.condition());
+        }
+
+        graph
+    }
+
+    fn add_node(&mut self, id: String, kind: NodeKind, label: &str) {
+        self.nodes.push(Node {
+            id,
+            kind,
+            label: label.to_string(),
+        });
+    }
+
+    fn add_edge(&mut self, from: String, to: String, kind: EdgeKind, label: String) {
+        self.edges.push(Edge {
+            from,
+            to,
+            kind,
+            label,
+        });
+    }
+
+    // Conditions point from the referenced object to the object they unlock.
+    fn add_conditions(&mut self, node: String, conds: &[Condition]) {
+        for leaf in conds.iter().flat_map(|c| c.leaves()) {
+            let from = match leaf.comparison().and_then(|(stat, _, _)| stat_node(&stat)) {
+                Some(from) => from,
+                None => continue,
+            };
+
+            self.add_edge(
+                from,
+                node.clone(),
+                EdgeKind::Condition,
+                format!("condition {}", condition_label(leaf)),
+            );
+        }
+    }
+
+    // The money and objective nodes are left out of the output
+    // if nothing references them, f.e in a game that never uses money.
+    fn is_visible(&self, node: &Node) -> bool {
+        !matches!(node.kind, NodeKind::Money | NodeKind::Objective)
+            || self
+                .edges
+                .iter()
+                .any(|e| e.from == node.id || e.to == node.id)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph economy {{");
+        let _ = writeln!(out, "    rankdir=LR;");
+
+        for node in self.nodes.iter().filter(|n| self.is_visible(n)) {
+            let shape = match node.kind {
+                NodeKind::Money | NodeKind::Currency => "ellipse",
+                NodeKind::Objective => "doubleoctagon",
+                NodeKind::Material => "box",
+                NodeKind::Product => "box3d",
+                NodeKind::Perk => "hexagon",
+                NodeKind::Automation => "component",
+                NodeKind::Badge => "star",
+                NodeKind::LoseCondition => "octagon",
+                NodeKind::Stage => "cds",
+            };
+            let _ = writeln!(
+                out,
+                "    {} [label=\"{}\", shape={}];",
+                node.id,
+                escape(&node.label),
+                shape
+            );
+        }
+
+        for edge in self.edges.iter() {
+            let style = match edge.kind {
+                EdgeKind::Relation | EdgeKind::Currency => "solid",
+                EdgeKind::Unlocks => "bold",
+                EdgeKind::Output => "tapered",
+                EdgeKind::Perk => "dashed",
+                EdgeKind::Automates => "solid",
+                EdgeKind::Condition => "dotted",
+            };
+            let _ = writeln!(
+                out,
+                "    {} -> {} [label=\"{}\", style={}];",
+                edge.from,
+                edge.to,
+                escape(&edge.label),
+                style
+            );
+        }
+
+        let _ = writeln!(out, "}}");
+
+        out
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let escape = |s: &str| s.replace('"', "#quot;");
+
+        let mut out = String::new();
+        let _ = writeln!(out, "flowchart LR");
+
+        for node in self.nodes.iter().filter(|n| self.is_visible(n)) {
+            let (open, close) = match node.kind {
+                NodeKind::Money | NodeKind::Currency => ("((", "))"),
+                NodeKind::Objective => ("[/", "/]"),
+                NodeKind::Material => ("[", "]"),
+                NodeKind::Product => ("([", "])"),
+                NodeKind::Perk => ("{{", "}}"),
+                NodeKind::Automation => ("[[", "]]"),
+                NodeKind::Badge => (">", "]"),
+                NodeKind::LoseCondition => ("[\\", "\\]"),
+                NodeKind::Stage => ("[/", "\\]"),
+            };
+            let _ = writeln!(
+                out,
+                "    {}{}\"{}\"{}",
+                node.id,
+                open,
+                escape(&node.label),
+                close
+            );
+        }
+
+        for edge in self.edges.iter() {
+            let arrow = match edge.kind {
+                EdgeKind::Relation | EdgeKind::Currency | EdgeKind::Automates => "-->",
+                EdgeKind::Unlocks => "==>",
+                EdgeKind::Output => "--o",
+                EdgeKind::Perk | EdgeKind::Condition => "-.->",
+            };
+            let _ = writeln!(
+                out,
+                "    {} {}|\"{}\"| {}",
+                edge.from,
+                arrow,
+                escape(&edge.label),
+                edge.to
+            );
+        }
+
+        out
+    }
+}
+
+impl State {
+    // Exports the dependency graph of the game as Graphviz DOT.
+    pub fn to_dot(&self) -> String {
+        Graph::new(self).to_dot()
+    }
+
+    // Exports the dependency graph of the game as a Mermaid flowchart.
+    pub fn to_mermaid(&self) -> String {
+        Graph::new(self).to_mermaid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::loader::load;
+
+    #[test]
+    fn export_lemonstand() {
+        let state = load(Path::new("res/lemonstand.yml")).unwrap();
+
+        let dot = state.to_dot();
+        assert!(dot.starts_with("digraph economy {"));
+        assert!(dot.contains("p0 -> m1 [label=\"Consumes 2\", style=solid];"));
+        assert!(dot.contains("k0 -> p0"));
+        assert!(dot.contains("a1 -> m1 [label=\"buys\", style=solid];"));
+
+        let mermaid = state.to_mermaid();
+        assert!(mermaid.starts_with("flowchart LR"));
+        assert!(mermaid.contains("p0([\"Lemonade\"])"));
+        assert!(mermaid.contains("p0 -.->|\"condition 200 Sold\"| objective"));
+    }
+}
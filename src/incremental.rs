@@ -1,26 +1,34 @@
+use anyhow::{anyhow, Result};
 use derive_getters::Getters;
 use either::Either;
 use mexprp::{Answer, Context};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::time::Duration;
 
+use crate::parser::parse_condition;
 use crate::prelude::{AutomationId, Expr};
-use crate::serde::ProductMaterialDef;
-use crate::types::{Count, PerkId, Price, ProductId, ProductMaterialId};
+use crate::serde::{PerkTargetDef, ProductMaterialDef};
+use crate::types::{
+    BadgeId, ChallengeId, Count, CurrencyId, LoseConditionId, PerkId, Price, ProductId,
+    ProductMaterialId, StageId,
+};
 
 use crate::timer::Timer;
 
 // `Quantity` represents a quantity of some asset
-// be it Money, Material or Product.
+// be it Money, Currency, Material or Product.
 //
 // Quantity::Money represents amount of money
+// Quantity::Currency represents amount of one of the declared currencies
 // Quantity::Material represent amount of a material
 // Quantity::Product represent amount of a product. Depending on the context
 // this may be used as the amount produced, current amount or amount sold.
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum Quantity {
     Money(Price),
+    Currency(CurrencyId, Price),
     Material(ProductMaterialId, Count),
     Product(
         ProductId,
@@ -38,11 +46,30 @@ impl Quantity {
         std::mem::discriminant(self) == std::mem::discriminant(other)
     }
 
+    // Checks whether two Quantities are amounts of the same asset,
+    // i.e they are similar and refer to the same currency, material or product.
+    pub fn same_asset(&self, other: &Quantity) -> bool {
+        match (self, other) {
+            (Quantity::Money(_), Quantity::Money(_)) => true,
+            (Quantity::Currency(x, _), Quantity::Currency(y, _)) => x == y,
+            (Quantity::Material(x, _), Quantity::Material(y, _)) => x == y,
+            (Quantity::Product(x, _, _), Quantity::Product(y, _, _)) => x == y,
+            _ => false,
+        }
+    }
+
     // Performs an operation on the internal amounts only if the two quantities
     // represent the same thing.
     pub fn op(&self, other: &Quantity, op: fn(f64, f64) -> f64) -> Quantity {
         match (self, other) {
             (Quantity::Money(x), Quantity::Money(y)) => Quantity::Money(op(*x, *y)),
+            (Quantity::Currency(x, y), Quantity::Currency(z, w)) => {
+                if x == z {
+                    Quantity::Currency(*x, op(*y, *w))
+                } else {
+                    *self
+                }
+            }
             (Quantity::Material(x, y), Quantity::Material(z, w)) => {
                 if x == z {
                     Quantity::Material(*x, op(*y as f64, *w as f64) as Count)
@@ -61,10 +88,34 @@ impl Quantity {
         }
     }
 
+    // Returns the same quantity multiplied `n` times.
+    pub fn times(&self, n: Count) -> Quantity {
+        match *self {
+            Quantity::Money(x) => Quantity::Money(x * n as f64),
+            Quantity::Currency(id, x) => Quantity::Currency(id, x * n as f64),
+            Quantity::Material(id, x) => Quantity::Material(id, x.saturating_mul(n)),
+            Quantity::Product(id, x, kind) => Quantity::Product(id, x.saturating_mul(n), kind),
+        }
+    }
+
+    // Returns the same quantity with its amount mapped by `f`.
+    // Counts are rounded to the nearest whole unit.
+    pub fn map(&self, f: impl Fn(f64) -> f64) -> Quantity {
+        match *self {
+            Quantity::Money(x) => Quantity::Money(f(x)),
+            Quantity::Currency(id, x) => Quantity::Currency(id, f(x)),
+            Quantity::Material(id, x) => Quantity::Material(id, f(x as f64).round() as Count),
+            Quantity::Product(id, x, kind) => {
+                Quantity::Product(id, f(x as f64).round() as Count, kind)
+            }
+        }
+    }
+
     // Return the quantity stored by the instance
     pub fn quantity(&self) -> f64 {
         match &self {
             Quantity::Money(x) => *x,
+            Quantity::Currency(_, x) => *x,
             Quantity::Material(_, x) => *x as f64,
             Quantity::Product(_, x, _) => *x as f64,
         }
@@ -74,6 +125,9 @@ impl Quantity {
     pub fn as_str(&self, state: &State) -> String {
         match &self {
             Quantity::Money(x) => format!("${:.2}", *x),
+            Quantity::Currency(id, x) => {
+                format!("{:.2} {}", *x, state.currencies[*id].name.to_lowercase())
+            }
             Quantity::Material(id, cnt) => format!(
                 "{} {}{}",
                 *cnt,
@@ -90,14 +144,307 @@ impl Quantity {
     }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
 pub enum ProductConditionKind {
     Count,
     Sold,
     Produced,
 }
 
-pub type Condition = Quantity;
+// A value tracked by the game that conditions can compare against.
+// Values that are either present or not, f.e whether a badge is won,
+// are represented as 1.0 or 0.0.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Stat {
+    Money,
+
+    #[doc = "Current amount of a currency."]
+    Currency(CurrencyId),
+
+    #[doc = "Current count of a material."]
+    Material(ProductMaterialId),
+
+    #[doc = "Total amount of a material bought, including the initial amount."]
+    MaterialBought(ProductMaterialId),
+
+    #[doc = "Total amount of a material lost to decay."]
+    MaterialDecayed(ProductMaterialId),
+
+    Product(ProductId, ProductConditionKind),
+
+    #[doc = "Total amount of a product lost to decay."]
+    ProductDecayed(ProductId),
+
+    #[doc = "Whether a badge is won."]
+    Badge(BadgeId),
+
+    #[doc = "Number of badges won."]
+    Badges,
+
+    #[doc = "Whether a perk is active."]
+    Perk(PerkId),
+
+    #[doc = "Whether an automation is bought."]
+    Automation(AutomationId),
+
+    #[doc = "Time played in seconds since the last prestige."]
+    TimePlayed,
+
+    #[doc = "Number of prestige resets done."]
+    Prestiges,
+
+    #[doc = "Whether a challenge was ever completed."]
+    Challenge(ChallengeId),
+
+    #[doc = "Price a product is sold at with the active perks applied, 0 if it's not sold."]
+    SellPrice(ProductId),
+
+    #[doc = "Interest towards a product with the active perks applied."]
+    Interest(ProductId),
+
+    #[doc = "Price of the next unit of a material with the active perks applied."]
+    MaterialPrice(ProductMaterialId),
+
+    #[doc = "Limit of a material with the active perks applied, infinite if it has none."]
+    MaterialLimit(ProductMaterialId),
+
+    #[doc = "Seconds between the runs of an automation with the active perks applied,"]
+    #[doc = "0 if it runs on every update."]
+    AutomationTimer(AutomationId),
+
+    #[doc = "Times an automation builds or buys per run with the active perks applied."]
+    AutomationBatch(AutomationId),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Comparison {
+    #[serde(alias = "<")]
+    Lt,
+    #[serde(alias = "<=")]
+    Le,
+    #[serde(alias = "==")]
+    Eq,
+    #[serde(alias = "!=")]
+    Ne,
+    #[serde(alias = ">=")]
+    Ge,
+    #[serde(alias = ">")]
+    Gt,
+}
+
+impl Comparison {
+    pub fn compare(&self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Ge => lhs >= rhs,
+            Comparison::Gt => lhs > rhs,
+        }
+    }
+
+    // Returns the comparison that holds exactly when this one doesn't.
+    pub fn negate(&self) -> Comparison {
+        match self {
+            Comparison::Lt => Comparison::Ge,
+            Comparison::Le => Comparison::Gt,
+            Comparison::Eq => Comparison::Ne,
+            Comparison::Ne => Comparison::Eq,
+            Comparison::Ge => Comparison::Lt,
+            Comparison::Gt => Comparison::Le,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Ge => ">=",
+            Comparison::Gt => ">",
+        }
+    }
+}
+
+// Condition on the state of the game. Used for unlocking
+// badges, perks and automations and for the game's objective.
+//
+// The `Money`, `Currency`, `Material` and `Product` variants are a shorthand for
+// "have at least" the given quantity, just like a [`Quantity`] is written.
+// Conditions may also be written as expressions, see [`crate::parser::parse_condition`].
+//
+// # Example
+// Sold 100 lemonades or own 5 shops, while not having the perk 0 bought:
+// ```
+// Condition::All(vec![
+//     Condition::Any(vec![
+//         Condition::Product(LEMONADE, 100, Some(ProductConditionKind::Sold)),
+//         Condition::Material(SHOP, 5),
+//     ]),
+//     Condition::Not(vec![Condition::Compare(Stat::Perk(0), Comparison::Eq, 1.0)]),
+// ])
+// ```
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Condition {
+    Money(Price),
+    Currency(CurrencyId, Price),
+    Material(ProductMaterialId, Count),
+    Product(
+        ProductId,
+        Count,
+        #[serde(default)] Option<ProductConditionKind>,
+    ),
+
+    #[doc = "Met when all of the conditions are met."]
+    All(Vec<Condition>),
+
+    #[doc = "Met when at least one of the conditions is met."]
+    Any(Vec<Condition>),
+
+    #[doc = "Met when none of the conditions are met."]
+    Not(Vec<Condition>),
+
+    #[doc = "Met when the stat compared to the value with the given comparison holds."]
+    Compare(Stat, Comparison, f64),
+
+    #[doc = "Condition written as an expression. Replaced with its parsed form"]
+    #[doc = "when the state is loaded, see [`State::compile_conditions`]."]
+    Expr(Expr),
+}
+
+impl From<Quantity> for Condition {
+    fn from(q: Quantity) -> Self {
+        match q {
+            Quantity::Money(money) => Condition::Money(money),
+            Quantity::Currency(id, amount) => Condition::Currency(id, amount),
+            Quantity::Material(id, cnt) => Condition::Material(id, cnt),
+            Quantity::Product(id, cnt, kind) => Condition::Product(id, cnt, kind),
+        }
+    }
+}
+
+impl Condition {
+    // Returns the comparison a leaf condition represents.
+    // The shorthand variants are treated as `Stat >= amount`.
+    // Returns None for `All`, `Any` and `Not`.
+    pub fn comparison(&self) -> Option<(Stat, Comparison, f64)> {
+        match self {
+            Condition::Money(money) => Some((Stat::Money, Comparison::Ge, *money)),
+            Condition::Currency(id, amount) => Some((Stat::Currency(*id), Comparison::Ge, *amount)),
+            Condition::Material(id, cnt) => {
+                Some((Stat::Material(*id), Comparison::Ge, *cnt as f64))
+            }
+            Condition::Product(id, cnt, kind) => Some((
+                Stat::Product(*id, kind.unwrap_or(ProductConditionKind::Produced)),
+                Comparison::Ge,
+                *cnt as f64,
+            )),
+            Condition::Compare(stat, cmp, value) => Some((*stat, *cmp, *value)),
+            _ => None,
+        }
+    }
+
+    // Returns all the leaf conditions of the condition tree.
+    pub fn leaves(&self) -> Vec<&Condition> {
+        match self {
+            Condition::All(conds) | Condition::Any(conds) | Condition::Not(conds) => {
+                conds.iter().flat_map(|c| c.leaves()).collect()
+            }
+            _ => vec![self],
+        }
+    }
+
+    // Return the string representation of the condition
+    pub fn as_str(&self, state: &State) -> String {
+        let join = |conds: &Vec<Condition>, sep: &str| {
+            conds
+                .iter()
+                .map(|c| c.as_str(state))
+                .collect::<Vec<_>>()
+                .join(sep)
+        };
+
+        match self {
+            Condition::Money(money) => Quantity::Money(*money).as_str(state),
+            Condition::Currency(id, amount) => Quantity::Currency(*id, *amount).as_str(state),
+            Condition::Material(id, cnt) => Quantity::Material(*id, *cnt).as_str(state),
+            Condition::Product(id, cnt, kind) => {
+                let kind = match kind.unwrap_or(ProductConditionKind::Produced) {
+                    ProductConditionKind::Count => "available",
+                    ProductConditionKind::Sold => "sold",
+                    ProductConditionKind::Produced => "produced",
+                };
+                format!(
+                    "{} {}",
+                    Quantity::Product(*id, *cnt, None).as_str(state),
+                    kind
+                )
+            }
+            Condition::All(conds) => format!("({})", join(conds, " and ")),
+            Condition::Any(conds) => format!("({})", join(conds, " or ")),
+            Condition::Not(conds) => format!("not ({})", join(conds, " or ")),
+            Condition::Compare(stat, cmp, value) => {
+                format!("{} {} {}", stat.as_str(state), cmp.symbol(), value)
+            }
+            Condition::Expr(expr) => expr.clone(),
+        }
+    }
+}
+
+impl Stat {
+    // Return the string representation of the stat
+    pub fn as_str(&self, state: &State) -> String {
+        match self {
+            Stat::Money => "money".to_string(),
+            Stat::Currency(id) => state.currencies[*id].name.to_lowercase(),
+            Stat::Material(id) => format!("{}s", state.materials[*id].name.to_lowercase()),
+            Stat::MaterialBought(id) => {
+                format!("{}s bought", state.materials[*id].name.to_lowercase())
+            }
+            Stat::MaterialDecayed(id) => {
+                format!("{}s decayed", state.materials[*id].name.to_lowercase())
+            }
+            Stat::ProductDecayed(id) => {
+                format!("{}s decayed", state.products[*id].name.to_lowercase())
+            }
+            Stat::Product(id, kind) => {
+                let kind = match kind {
+                    ProductConditionKind::Count => "available",
+                    ProductConditionKind::Sold => "sold",
+                    ProductConditionKind::Produced => "produced",
+                };
+                format!("{}s {}", state.products[*id].name.to_lowercase(), kind)
+            }
+            Stat::Badge(id) => format!("badge {}", state.badges[*id].name),
+            Stat::Badges => "badges".to_string(),
+            Stat::Perk(id) => format!("perk {}", state.perks[*id].name),
+            Stat::Automation(id) => format!("automation {}", state.automations[*id].name),
+            Stat::TimePlayed => "time played".to_string(),
+            Stat::Prestiges => "prestiges".to_string(),
+            Stat::Challenge(id) => format!("challenge {}", state.challenges[*id].name),
+            Stat::SellPrice(id) => {
+                format!("{} sell price", state.products[*id].name.to_lowercase())
+            }
+            Stat::Interest(id) => {
+                format!("{} interest", state.products[*id].name.to_lowercase())
+            }
+            Stat::MaterialPrice(id) => {
+                format!("{} price", state.materials[*id].name.to_lowercase())
+            }
+            Stat::MaterialLimit(id) => {
+                format!("{} limit", state.materials[*id].name.to_lowercase())
+            }
+            Stat::AutomationTimer(id) => {
+                format!("automation {} timer", state.automations[*id].name)
+            }
+            Stat::AutomationBatch(id) => {
+                format!("automation {} batch", state.automations[*id].name)
+            }
+        }
+    }
+}
 
 // Represents a relation kind
 // Let us have two objects A, B and a [`Relation`] with direction A -> B, then:
@@ -140,31 +487,45 @@ pub enum RelationKind {
 pub struct Relation {
     kind: RelationKind,
     quantity: Quantity,
+
+    #[doc = "Number of orders of a product with a build time the quantity works on at once."]
+    #[doc = "Only used by `ManufacturedBy` relations. 1 if None."]
+    #[serde(default)]
+    capacity: Option<Count>,
 }
 
 impl Relation {
     pub fn new(kind: RelationKind, quantity: Quantity) -> Self {
-        Self { kind, quantity }
-    }
-
-    pub fn needs(quantity: Quantity) -> Self {
         Self {
-            kind: RelationKind::Needs,
+            kind,
             quantity,
+            capacity: None,
         }
     }
 
+    pub fn needs(quantity: Quantity) -> Self {
+        Self::new(RelationKind::Needs, quantity)
+    }
+
     pub fn consumes(quantity: Quantity) -> Self {
-        Self {
-            kind: RelationKind::Consumes,
-            quantity,
-        }
+        Self::new(RelationKind::Consumes, quantity)
+    }
+
+    // Sets the number of orders the quantity works on at once.
+    // See [`State::slots`]
+    pub fn with_capacity(mut self, capacity: Count) -> Self {
+        self.capacity = Some(capacity);
+        self
     }
 
     pub fn kind(&self) -> RelationKind {
         self.kind
     }
 
+    pub fn capacity(&self) -> Option<Count> {
+        self.capacity
+    }
+
     pub fn quantity(&self) -> &Quantity {
         &self.quantity
     }
@@ -172,35 +533,258 @@ impl Relation {
     pub fn similar_quantity(&self, other: &Quantity) -> bool {
         self.quantity.similar(other)
     }
+}
 
-    fn add(&self, perk: Quantity) -> Relation {
-        let mut new_cond = *self;
-        new_cond.quantity = self.quantity.op(&perk, |x, y| x + y);
-        new_cond
+// A single dependency of a product as it is used during construction.
+// `base` is the quantity as defined in the product's dependencies, while
+// `effective` is the same quantity after all active perks are applied.
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct RecipeEntry {
+    #[getter(copy)]
+    kind: RelationKind,
+    #[getter(copy)]
+    base: Quantity,
+    #[getter(copy)]
+    effective: Quantity,
+}
+
+impl RecipeEntry {
+    // Whether an active perk changed the quantity of this entry.
+    pub fn modified(&self) -> bool {
+        self.base.quantity() != self.effective.quantity()
     }
+}
 
-    fn sub(&self, perk: Quantity) -> Relation {
-        let mut new_cond = *self;
-        new_cond.quantity = self.quantity.op(&perk, |x, y| x - y);
-        new_cond
+// A single byproduct of a product as it is yielded by one construction.
+// `base` is the quantity as defined in the product's outputs, while
+// `effective` is the same quantity after all active perks are applied.
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct RecipeOutput {
+    #[getter(copy)]
+    base: Quantity,
+    #[getter(copy)]
+    effective: Quantity,
+    #[doc = "Chance of the output being yielded, 1 unless it's a rare byproduct."]
+    #[getter(copy)]
+    chance: f64,
+}
+
+impl RecipeOutput {
+    // Whether an active perk changed the quantity of this output.
+    pub fn modified(&self) -> bool {
+        self.base.quantity() != self.effective.quantity()
     }
 
-    fn multiply(&self, perk: Quantity) -> Relation {
-        let mut new_cond = *self;
-        new_cond.quantity = self.quantity.op(&perk, |x, y| x * y);
-        new_cond
+    // Average amount of the output yielded by a single construction.
+    pub fn expected(&self) -> f64 {
+        self.chance * self.effective.quantity()
     }
+}
 
-    fn divide(&self, perk: Quantity) -> Relation {
-        let mut new_cond = *self;
-        new_cond.quantity = self.quantity.op(&perk, |x, y| x / y);
-        new_cond
+// Structured view of a product's recipe with the active perks applied.
+// Meant for UIs that want to render the recipe on their own, f.e
+// showing the quantities before and after a perk was bought.
+//
+// See [`State::effective_recipe`]
+#[derive(Clone, Debug, Getters)]
+pub struct Recipe {
+    #[getter(copy)]
+    product: ProductId,
+
+    #[doc = "All the dependencies of the product, in the order they were defined."]
+    entries: Vec<RecipeEntry>,
+
+    #[doc = "Byproducts of a single construction, in the order they were defined,"]
+    #[doc = "followed by the rare ones."]
+    outputs: Vec<RecipeOutput>,
+
+    #[doc = "Amount of the product built per construction without any perks."]
+    #[doc = "The lowest one if the yield is random."]
+    #[getter(copy)]
+    base_yield: Count,
+
+    #[doc = "Amount of the product built per construction with the active perks applied."]
+    #[doc = "The lowest one if the yield is random."]
+    #[getter(copy)]
+    effective_yield: Count,
+
+    #[doc = "Average amount of the product built per construction, see [`State::expected_yield`]."]
+    #[getter(copy)]
+    expected_yield: f64,
+
+    #[doc = "Chance of a construction being critical with the active perks applied."]
+    #[getter(copy)]
+    crit_chance: f64,
+
+    #[doc = "Price the product is sold at without any perks, None if it's not sold."]
+    #[getter(copy)]
+    base_price: Option<Price>,
+
+    #[doc = "Price the product is sold at with the active perks applied, see [`State::sell_price`]."]
+    #[getter(copy)]
+    sell_price: Option<Price>,
+
+    #[doc = "Interest towards the product with the active perks applied, see [`State::interest`]."]
+    #[getter(copy)]
+    interest: f64,
+}
+
+impl Recipe {
+    // Iterates over the entries with the given relation kind only.
+    pub fn entries_of(&self, kind: RelationKind) -> impl Iterator<Item = &RecipeEntry> {
+        self.entries.iter().filter(move |e| e.kind == kind)
     }
+}
 
-    fn set(&self, perk: Quantity) -> Relation {
-        let mut new_cond = *self;
-        new_cond.quantity = self.quantity.op(&perk, |_, y| y);
-        new_cond
+// Availability of a single dependency of a product.
+//
+// See [`BuildReport`]
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct DependencyReport {
+    #[getter(copy)]
+    kind: RelationKind,
+
+    #[doc = "Quantity needed for a single construction with the active perks applied."]
+    #[getter(copy)]
+    required: Quantity,
+
+    #[doc = "Amount of the required asset currently present."]
+    #[getter(copy)]
+    available: f64,
+
+    #[doc = "Number of constructions the available amount is enough for."]
+    #[getter(copy)]
+    max_count: Count,
+}
+
+impl DependencyReport {
+    pub fn satisfied(&self) -> bool {
+        self.max_count > 0
+    }
+}
+
+// Diagnostics explaining how many times a product can be constructed
+// and why construction may do nothing.
+//
+// See [`State::build_report`]
+#[derive(Clone, Debug, Getters)]
+pub struct BuildReport {
+    #[getter(copy)]
+    product: ProductId,
+
+    #[doc = "All the dependencies of the product, in the order they were defined."]
+    dependencies: Vec<DependencyReport>,
+
+    #[doc = "Index into `dependencies` of the one limiting the construction, if any."]
+    #[getter(copy)]
+    limiting: Option<usize>,
+
+    #[doc = "Maximum number of constructions that can be done right now."]
+    #[getter(copy)]
+    max_builds: Count,
+
+    #[doc = "Amount of the product `max_builds` constructions would yield at the very least."]
+    #[doc = "Random yields and critical constructions may add to it."]
+    #[getter(copy)]
+    max_yield: Count,
+
+    #[doc = "Byproducts `max_builds` constructions would yield, without the rare ones."]
+    outputs: Vec<Quantity>,
+}
+
+impl BuildReport {
+    pub fn limiting_dependency(&self) -> Option<&DependencyReport> {
+        self.limiting.map(|i| &self.dependencies[i])
+    }
+
+    pub fn can_build(&self) -> bool {
+        self.max_builds > 0
+    }
+}
+
+// Diagnostics explaining whether a material can be bought.
+//
+// See [`State::buy_report`]
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct BuyReport {
+    #[getter(copy)]
+    material: ProductMaterialId,
+
+    #[doc = "Current price of a single unit of the material."]
+    #[getter(copy)]
+    price: Price,
+
+    #[doc = "Currency the material is priced in. None for money."]
+    #[getter(copy)]
+    currency: Option<CurrencyId>,
+
+    #[doc = "Amount of the currency currently available."]
+    #[getter(copy)]
+    money: f64,
+
+    #[doc = "Whether the material's limit is already reached."]
+    #[getter(copy)]
+    limit_reached: bool,
+}
+
+impl BuyReport {
+    pub fn can_buy(&self) -> bool {
+        !self.limit_reached && self.money >= self.price
+    }
+}
+
+// Currency declared by the game in addition to money,
+// f.e reputation or research points.
+// Referenced by its index in the `currencies` list of the [`State`].
+#[derive(Clone, Debug, Getters, Deserialize, Serialize)]
+pub struct Currency {
+    #[getter(skip)]
+    name: String,
+
+    #[doc = "Current amount of the currency."]
+    #[getter(copy)]
+    #[serde(default, alias = "init")]
+    amount: Price,
+}
+
+impl Currency {
+    pub fn new(name: String, init: Price) -> Self {
+        Self { name, amount: init }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// Most random yields rolled or averaged at once, see [`State::expected_yield`].
+const MAX_ROLLS: Count = 1000;
+
+// Clamps a probability between 0 and 1, undefined or infinite ones being 0.
+fn chance(value: f64) -> f64 {
+    if !value.is_finite() {
+        return 0.0;
+    }
+
+    value.clamp(0.0, 1.0)
+}
+
+// Fraction of the price refunded when selling a material
+// which doesn't define its own refund.
+pub const DEFAULT_REFUND: f64 = 0.5;
+
+// Evaluates a designer-defined expression with the given variables.
+// If the expression is incorrect it evaluates to 0.0
+fn eval_expr(expr: &Expr, vars: &[(&str, f64)]) -> f64 {
+    let mut ctx = Context::new();
+    for (name, value) in vars {
+        ctx.set_var(name, *value);
+    }
+
+    match mexprp::eval_ctx(expr, &ctx) {
+        Ok(Answer::Single(ans)) => ans,
+        Ok(Answer::Multiple(answers)) => answers.into_iter().find(|a| *a > 0.0).unwrap_or(0.0),
+        _ => 0.0,
     }
 }
 
@@ -208,6 +792,8 @@ impl Relation {
 // Each material has a base price and a growth factor that
 // determine the current price of the material based on the amount
 // of it already bought.
+// Bought materials may be sold back for a refund, which by default is
+// a fraction of the price they were bought at, see [`ProductMaterial::refund`].
 // Materials are used during the manufacturing or selling of a product.
 // F.e ProductMaterial may be a Shop which sells certain product.
 // In that case the product will have a dependency to that material
@@ -220,9 +806,19 @@ pub struct ProductMaterial {
     pub(crate) base_price: Price,
     bought: Count,
     count: Count,
+    paid: Vec<Price>, // prices the bought units in stock were paid, the latest last
     limit: Option<Count>,
     pub(crate) growth: Either<f64, Expr>,
-    pub(crate) active: bool, // wether or not the product unlocked for the player
+    pub(crate) refund: Either<f64, Expr>,
+    pub(crate) currency: Option<CurrencyId>, // currency the material is bought with, money if None
+    pub(crate) regeneration: f64,            // amount regenerated per second
+    regenerated: f64,                        // fraction of a unit regenerated so far
+    pub(crate) decay: Option<Decay>,
+    spoilage: Spoilage,
+    decayed: Count,
+    pub(crate) upkeep: Price, // money paid per unit per second
+    growth_scale: f64,        // multiplier of `x` in the growth, set by challenges
+    pub(crate) active: bool,  // wether or not the product unlocked for the player
 }
 
 impl ProductMaterial {
@@ -239,45 +835,138 @@ impl ProductMaterial {
             base_price,
             bought: init_bought,
             count: init_bought,
+            paid: Vec::new(),
             limit,
             growth,
+            refund: Either::Left(DEFAULT_REFUND),
+            currency: None,
+            regeneration: 0.0,
+            regenerated: 0.0,
+            decay: None,
+            spoilage: Spoilage::default(),
+            decayed: 0,
+            upkeep: 0.0,
+            growth_scale: 1.0,
             active: unlocked,
         }
     }
 
+    // Makes each unit of the material cost `upkeep` money per second, f.e rent of a shop.
+    pub fn with_upkeep(mut self, upkeep: Price) -> Self {
+        self.upkeep = upkeep;
+        self
+    }
+
+    // Sets the refund given when selling the material. Either a fraction
+    // of the price paid or an expression of `x`, the amount bought after the sale,
+    // and `price`, the price the unit was paid.
+    pub fn with_refund(mut self, refund: Either<f64, Expr>) -> Self {
+        self.refund = refund;
+        self
+    }
+
+    // Makes the material go bad over time.
+    pub fn with_decay(mut self, decay: Decay) -> Self {
+        self.decay = Some(decay);
+        self
+    }
+
+    // Makes the material regenerate for free at `rate` units per second, up to its limit.
+    pub fn with_regeneration(mut self, rate: f64) -> Self {
+        self.regeneration = rate;
+        self
+    }
+
+    // Prices the material in one of the declared currencies instead of money.
+    pub fn with_currency(mut self, currency: CurrencyId) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
     pub fn price(&self) -> Price {
+        self.price_at(self.bought)
+    }
+
+    // Price of the material after `bought` amount of it was bought.
+    // Doesn't take perks into account, see [`State::material_price_at`].
+    pub fn price_at(&self, bought: Count) -> Price {
+        self.price_scaled(bought, self.growth_scale)
+    }
+
+    // Price of the material after `bought` amount of it was bought
+    // with `x` of the growth multiplied by `scale`.
+    pub(crate) fn price_scaled(&self, bought: Count, scale: f64) -> Price {
+        let x = bought as f64 * scale;
         self.base_price
             * match &self.growth {
-                Either::Left(growth) => growth.powf((self.bought as f64) / 10.0),
-                Either::Right(expr) => {
-                    let mut ctx = Context::new();
-                    ctx.set_var("x", self.bought as f64);
-
-                    let ans = match mexprp::eval_ctx(expr, &ctx) {
-                        Ok(Answer::Single(ans)) => ans,
-                        Ok(Answer::Multiple(answers)) => {
-                            let mut ans = 0.0;
-                            for a in answers {
-                                if a > 0.0 {
-                                    ans = a;
-                                    break;
-                                }
-                            }
-                            ans
-                        }
-                        _ => 0.0,
-                    };
+                Either::Left(growth) => growth.powf(x / 10.0),
+                Either::Right(expr) => eval_expr(expr, &[("x", x)]),
+            }
+    }
 
-                    ans
+    // Total price of buying `cnt` more units of the material after `bought` amount
+    // of it was bought, see [`ProductMaterial::price_scaled`].
+    // Prices growing by an expression are only summed up to `MAX_PRICES` units,
+    // None if more are bought.
+    pub(crate) fn cost_scaled(&self, bought: Count, cnt: Count, scale: f64) -> Option<Price> {
+        const MAX_PRICES: Count = 1000;
+
+        match &self.growth {
+            Either::Left(growth) => {
+                let ratio = growth.powf(scale / 10.0);
+                let first = self.price_scaled(bought, scale);
+                if ratio == 1.0 {
+                    Some(first * cnt as f64)
+                } else {
+                    Some(first * (ratio.powf(cnt as f64) - 1.0) / (ratio - 1.0))
                 }
             }
+            Either::Right(_) if cnt > MAX_PRICES => None,
+            Either::Right(_) => Some((0..cnt).map(|i| self.price_scaled(bought + i, scale)).sum()),
+        }
+    }
+
+    // Refund for selling a single unit of the material right now.
+    // Paid units are sold first and refunded based on the price they were paid,
+    // the rest, like the initial, regenerated or produced ones, are refunded nothing.
+    // It never exceeds the price the unit was paid.
+    pub fn refund(&self) -> Price {
+        let Some(&price) = self.paid.get(self.paid_units().wrapping_sub(1)) else {
+            return 0.0;
+        };
+
+        let refund = match &self.refund {
+            Either::Left(fraction) => fraction * price,
+            Either::Right(expr) => {
+                eval_expr(expr, &[("x", (self.bought - 1) as f64), ("price", price)])
+            }
+        };
+
+        refund.clamp(0.0, price)
+    }
+
+    // Number of paid units still in stock.
+    // Units used up by constructions count as paid ones first.
+    fn paid_units(&self) -> usize {
+        self.paid.len().min(self.count as usize)
     }
 
-    pub(crate) fn buy(&mut self) {
+    pub(crate) fn buy(&mut self, price: Price) {
+        self.paid.truncate(self.paid_units());
+        self.paid.push(price);
         self.bought += 1;
         self.count += 1;
     }
 
+    // Units which weren't paid, f.e regenerated ones, don't lower the price.
+    pub(crate) fn sell(&mut self) {
+        self.paid.truncate(self.paid_units());
+        if self.paid.pop().is_some() {
+            self.bought -= 1;
+        }
+        self.count -= 1;
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -290,10 +979,37 @@ impl ProductMaterial {
         self.count
     }
 
+    pub fn bought(&self) -> Count {
+        self.bought
+    }
+
     pub fn limit(&self) -> Option<Count> {
         self.limit
     }
 
+    pub fn currency(&self) -> Option<CurrencyId> {
+        self.currency
+    }
+
+    // Base regeneration rate per second, see [`State::regeneration`] for the effective one.
+    pub fn regeneration(&self) -> f64 {
+        self.regeneration
+    }
+
+    // Base decay, see [`State::material_decay`] for the effective one.
+    pub fn decay(&self) -> Option<Decay> {
+        self.decay
+    }
+
+    // Total amount of the material lost to decay.
+    pub fn decayed(&self) -> Count {
+        self.decayed
+    }
+
+    pub fn upkeep(&self) -> Price {
+        self.upkeep
+    }
+
     pub(crate) fn activate(&mut self) {
         self.active = true;
     }
@@ -308,7 +1024,7 @@ impl ProductMaterial {
 // ```
 // Perk::new(..., perk: (Quantity::Material(<the material id>, 2), PerkKind::Divide))
 // ```
-#[derive(PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize, Serialize)]
 pub enum PerkKind {
     Set,
     Add,
@@ -317,47 +1033,269 @@ pub enum PerkKind {
     Divide,
 }
 
-// Defines a perk that may be applied during the production of a product.
-#[derive(Deserialize, Serialize)]
-pub struct Perk {
-    name: String,
-    #[serde(alias = "desc")]
-    description: String,
+impl PerkKind {
+    // Applies the perk to a plain value.
+    pub fn apply(&self, value: f64, by: f64) -> f64 {
+        match self {
+            PerkKind::Set => by,
+            PerkKind::Add => value + by,
+            PerkKind::Subtract => value - by,
+            PerkKind::Multiply => value * by,
+            PerkKind::Divide => value / by,
+        }
+    }
 
-    #[doc = "List of condition of unlocking the perk to the user"]
-    condition: Vec<Condition>, // Always a Needs relation
+    // Value a perk of the given level applies by, i.e the perk applied `level` times.
+    // Set perks don't stack, so they are applied by the same value on every level.
+    pub fn stacked(&self, by: f64, level: Count) -> f64 {
+        match self {
+            PerkKind::Set => by,
+            PerkKind::Add | PerkKind::Subtract => by * level as f64,
+            PerkKind::Multiply | PerkKind::Divide => by.powf(level as f64),
+        }
+    }
 
-    #[doc = "List of quantities that will be consumed after buying the perk"]
-    buy_price: Vec<Quantity>, // Always a Consume relation
+    // Stage of the stacking order the perk is applied in, see [`State::breakdown`].
+    fn stage(&self) -> u8 {
+        match self {
+            PerkKind::Set => 0,
+            PerkKind::Add | PerkKind::Subtract => 1,
+            PerkKind::Multiply | PerkKind::Divide => 2,
+        }
+    }
+}
 
-    perk: (Quantity, PerkKind),
+// What a perk modifies in the products it is attached to.
+//
+// A `Quantity` modifies the matching dependencies and byproducts of the product,
+// or the amount of the product yielded by a construction if it's the product itself.
+// In definitions the quantity is written directly, f.e `[!Material [1, 2], Divide]`.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "PerkTargetDef")]
+#[serde(into = "PerkTargetDef")]
+pub enum PerkTarget {
+    Quantity(Quantity),
 
-    #[serde(skip)]
-    unlocked: bool,
+    #[doc = "Chance of a construction being critical. See [`RandomYield`]"]
+    CritChance(f64),
 
-    #[serde(skip)]
-    active: bool,
-}
+    #[doc = "Regeneration rate of a material per second. Applies while the perk is active,"]
+    #[doc = "without being attached to a product."]
+    Regeneration(ProductMaterialId, f64),
 
-impl Perk {
-    pub fn new(
+    #[doc = "How fast a material spoils, see [`Decay::rate`]. Applies like `Regeneration`."]
+    MaterialDecay(ProductMaterialId, f64),
+
+    #[doc = "How fast a product spoils, see [`Decay::rate`]. Applies like `Regeneration`."]
+    ProductDecay(ProductId, f64),
+
+    #[doc = "Price the products are sold at, see [`State::sell_price`]."]
+    SellPrice(f64),
+
+    #[doc = "Interest towards the products, see [`State::interest`]."]
+    Interest(f64),
+
+    #[doc = "Limit of a material, see [`State::material_limit`]. Applies like `Regeneration`"]
+    #[doc = "and only to materials that have a limit."]
+    MaterialLimit(ProductMaterialId, f64),
+
+    #[doc = "Rate the price of a material grows at, see [`State::material_growth`]."]
+    #[doc = "Applies like `Regeneration`."]
+    MaterialGrowth(ProductMaterialId, f64),
+
+    #[doc = "Seconds between the runs of an automation, see [`State::automation_timer`]."]
+    #[doc = "Applies to every automation if the perk's scope is [`PerkScope::Automations`]."]
+    AutomationTimer(AutomationId, f64),
+
+    #[doc = "Times an automation builds or buys per run, see [`State::automation_batch`]."]
+    #[doc = "Applies like `AutomationTimer`."]
+    AutomationBatch(AutomationId, f64),
+}
+
+impl PerkTarget {
+    // Returns the same target with its value mapped by `f`.
+    pub fn map(self, f: impl Fn(f64) -> f64) -> PerkTarget {
+        match self {
+            PerkTarget::Quantity(q) => PerkTarget::Quantity(q.map(f)),
+            PerkTarget::CritChance(chance) => PerkTarget::CritChance(f(chance)),
+            PerkTarget::Regeneration(id, rate) => PerkTarget::Regeneration(id, f(rate)),
+            PerkTarget::MaterialDecay(id, by) => PerkTarget::MaterialDecay(id, f(by)),
+            PerkTarget::ProductDecay(id, by) => PerkTarget::ProductDecay(id, f(by)),
+            PerkTarget::SellPrice(by) => PerkTarget::SellPrice(f(by)),
+            PerkTarget::Interest(by) => PerkTarget::Interest(f(by)),
+            PerkTarget::MaterialLimit(id, by) => PerkTarget::MaterialLimit(id, f(by)),
+            PerkTarget::MaterialGrowth(id, by) => PerkTarget::MaterialGrowth(id, f(by)),
+            PerkTarget::AutomationTimer(id, by) => PerkTarget::AutomationTimer(id, f(by)),
+            PerkTarget::AutomationBatch(id, by) => PerkTarget::AutomationBatch(id, f(by)),
+        }
+    }
+}
+
+impl From<Quantity> for PerkTarget {
+    fn from(q: Quantity) -> Self {
+        PerkTarget::Quantity(q)
+    }
+}
+
+// Group of products or automations a perk applies to.
+// Perks attached to a product through its `perks` always apply to it,
+// the scope allows a single perk to apply to many products at once.
+// Perks targeting automation timers or batches apply to the automation
+// they name, or to all of them with the `Automations` scope.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum PerkScope {
+    #[doc = "Only the products listing the perk in their `perks`."]
+    #[default]
+    Attached,
+
+    #[doc = "Every product."]
+    AllProducts,
+
+    #[doc = "Every product with the given tag."]
+    Tag(String),
+
+    #[doc = "Every product whose recipe uses the given material."]
+    Material(ProductMaterialId),
+
+    #[doc = "Every product built by an automation."]
+    Automated,
+
+    #[doc = "Every automation, f.e all machines running faster. Only affects"]
+    #[doc = "`AutomationTimer` and `AutomationBatch` perks, never products."]
+    Automations,
+}
+
+// Default maximum level of a perk, i.e it can be bought only once.
+fn default_max_level() -> Option<Count> {
+    Some(1)
+}
+
+fn default_cost_growth() -> Either<f64, Expr> {
+    Either::Left(1.0)
+}
+
+// Defines a perk that may be applied during the production of a product.
+//
+// A perk may be bought repeatedly up to its maximum level, each level
+// stacking the effect once more, f.e a `Multiply` by 1.1 perk at level 3
+// multiplies by 1.1^3. The price of the next level grows with the level
+// bought so far, see [`Perk::price_at`].
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Perk {
+    name: String,
+    #[serde(alias = "desc")]
+    description: String,
+
+    #[doc = "List of condition of unlocking the perk to the user"]
+    condition: Vec<Condition>, // Always a Needs relation
+
+    #[doc = "List of quantities that will be consumed after buying the perk"]
+    buy_price: Vec<Quantity>, // Always a Consume relation
+
+    perk: (PerkTarget, PerkKind),
+
+    #[doc = "Whether the perk is kept by prestige resets, i.e it is a permanent upgrade."]
+    #[serde(default)]
+    persistent: bool,
+
+    #[doc = "Products the perk applies to besides the ones it's attached to."]
+    #[serde(default)]
+    scope: PerkScope,
+
+    #[doc = "Perks that need to be active before the perk unlocks, i.e its parents in the perk tree."]
+    #[serde(default)]
+    requires: Vec<PerkId>,
+
+    #[doc = "Perks the perk is exclusive with. Once one of them is active the others are locked for good."]
+    #[serde(default)]
+    excludes: Vec<PerkId>,
+
+    #[doc = "Order of the perk among the perks of the same kind modifying the same value,"]
+    #[doc = "lower ones are applied first. See [`State::breakdown`]"]
+    #[serde(default)]
+    priority: i32,
+
+    #[doc = "Maximum level the perk can be bought to, unlimited if None."]
+    #[serde(default = "default_max_level")]
+    max_level: Option<Count>,
+
+    #[doc = "Growth of the price per level. Either a factor the price is multiplied by"]
+    #[doc = "for every level already bought or an expression of `x`, the level already bought."]
+    #[serde(default = "default_cost_growth", with = "either::serde_untagged")]
+    cost_growth: Either<f64, Expr>,
+
+    #[serde(skip)]
+    unlocked: bool,
+
+    #[doc = "Level the perk has been bought to, the perk is active if it's above 0."]
+    #[serde(default)]
+    level: Count,
+}
+
+impl Perk {
+    pub fn new(
         name: String,
         description: String,
         condition: Vec<Condition>,
         buy_price: Vec<Quantity>,
-        perk: (Quantity, PerkKind),
+        perk: (impl Into<PerkTarget>, PerkKind),
     ) -> Self {
         Self {
             name,
             description,
             condition: condition,
             buy_price,
-            perk,
+            perk: (perk.0.into(), perk.1),
+            persistent: false,
+            scope: PerkScope::Attached,
+            requires: Vec::new(),
+            excludes: Vec::new(),
+            priority: 0,
+            max_level: default_max_level(),
+            cost_growth: default_cost_growth(),
             unlocked: false,
-            active: false,
+            level: 0,
         }
     }
 
+    // Applies the perk to a whole group of products.
+    pub fn with_scope(mut self, scope: PerkScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    // Makes the perk unlock only once all the `requires` perks are active.
+    pub fn with_requires(mut self, requires: Vec<PerkId>) -> Self {
+        self.requires = requires;
+        self
+    }
+
+    // Makes the perk exclusive with the `excludes` perks, see [`State::perk_locked`].
+    pub fn with_excludes(mut self, excludes: Vec<PerkId>) -> Self {
+        self.excludes = excludes;
+        self
+    }
+
+    // Orders the perk among the perks of the same kind, see [`State::breakdown`].
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // Makes the perk buyable repeatedly up to `max_level`, unlimited if None,
+    // with the price growing per level by `cost_growth`.
+    pub fn with_levels(mut self, max_level: Option<Count>, cost_growth: Either<f64, Expr>) -> Self {
+        self.max_level = max_level;
+        self.cost_growth = cost_growth;
+        self
+    }
+
+    // Keeps the perk across prestige resets.
+    pub fn with_persistent(mut self) -> Self {
+        self.persistent = true;
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -370,32 +1308,367 @@ impl Perk {
         &self.condition
     }
 
+    // Price of the first level of the perk.
     pub fn price(&self) -> &Vec<Quantity> {
         &self.buy_price
     }
 
+    // Price of the next level of the perk after `level` levels were bought.
+    pub fn price_at(&self, level: Count) -> Vec<Quantity> {
+        let x = level as f64;
+        let growth = match &self.cost_growth {
+            Either::Left(growth) => growth.powf(x),
+            Either::Right(expr) => eval_expr(expr, &[("x", x)]),
+        };
+
+        self.buy_price
+            .iter()
+            .map(|q| q.map(|v| v * growth))
+            .collect()
+    }
+
+    // Price of buying the next level of the perk.
+    pub fn next_price(&self) -> Vec<Quantity> {
+        self.price_at(self.level)
+    }
+
     pub fn unlocked(&self) -> bool {
         self.unlocked
     }
 
     pub fn active(&self) -> bool {
-        self.active
+        self.level > 0
+    }
+
+    pub fn level(&self) -> Count {
+        self.level
+    }
+
+    pub fn max_level(&self) -> Option<Count> {
+        self.max_level
     }
 
-    pub(crate) fn perk(&self) -> (Quantity, PerkKind) {
+    // Whether the perk can't be bought any further.
+    pub fn maxed(&self) -> bool {
+        self.max_level.is_some_and(|max| self.level >= max)
+    }
+
+    pub fn persistent(&self) -> bool {
+        self.persistent
+    }
+
+    pub fn scope(&self) -> &PerkScope {
+        &self.scope
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn requires(&self) -> &[PerkId] {
+        &self.requires
+    }
+
+    pub fn excludes(&self) -> &[PerkId] {
+        &self.excludes
+    }
+
+    pub(crate) fn perk(&self) -> (PerkTarget, PerkKind) {
         self.perk
     }
 
+    // The perk's target scaled to its current level.
+    pub(crate) fn effect(&self) -> (PerkTarget, PerkKind) {
+        let (target, kind) = self.perk;
+        (target.map(|by| kind.stacked(by, self.level)), kind)
+    }
+
     pub(crate) fn unlock(&mut self) {
         self.unlocked = true;
     }
 
     pub(crate) fn activate(&mut self) {
-        self.active = true;
+        self.level = self.level.max(1);
+    }
+
+    fn level_up(&mut self) {
+        self.level += 1;
     }
 }
 
-#[derive(Deserialize, Serialize)]
+// A perk as a node of the perk tree, meant for UIs laying the tree out.
+//
+// See [`State::perk_tree`]
+#[derive(Clone, Debug, Getters)]
+pub struct PerkNode {
+    #[getter(copy)]
+    perk: PerkId,
+
+    #[doc = "Perks that need to be active before this one unlocks."]
+    parents: Vec<PerkId>,
+
+    #[doc = "Perks that need this one to be active before they unlock."]
+    children: Vec<PerkId>,
+
+    #[doc = "Perks this one is exclusive with, no matter which of them declares it."]
+    exclusive: Vec<PerkId>,
+
+    #[doc = "Length of the longest chain of parents, 0 for the roots of the tree."]
+    #[getter(copy)]
+    depth: usize,
+
+    #[doc = "Whether an exclusive perk is active, so this one can never be bought."]
+    #[getter(copy)]
+    locked: bool,
+}
+
+// A value the perks may modify. See [`State::breakdown`]
+#[derive(Copy, Clone, Debug)]
+pub enum PerkValue {
+    #[doc = "A quantity in a product's recipe, i.e one of its dependencies,"]
+    #[doc = "its byproducts or the amount of the product itself a construction yields."]
+    Quantity(ProductId, Quantity),
+    CritChance(ProductId),
+    SellPrice(ProductId),
+    Interest(ProductId),
+    Regeneration(ProductMaterialId),
+    MaterialDecay(ProductMaterialId),
+    ProductDecay(ProductId),
+    MaterialLimit(ProductMaterialId),
+    MaterialGrowth(ProductMaterialId),
+    AutomationTimer(AutomationId),
+    AutomationBatch(AutomationId),
+}
+
+// A single perk applied to a value, see [`PerkBreakdown`].
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct PerkStep {
+    #[getter(copy)]
+    perk: PerkId,
+    #[getter(copy)]
+    kind: PerkKind,
+    #[doc = "Value the perk is applied by, scaled to the perk's level."]
+    #[getter(copy)]
+    by: f64,
+    #[getter(copy)]
+    before: f64,
+    #[getter(copy)]
+    after: f64,
+}
+
+impl PerkStep {
+    // How much the perk changed the value.
+    pub fn contribution(&self) -> f64 {
+        self.after - self.before
+    }
+}
+
+// How the active perks modify a value, meant for debugging perk setups.
+// `steps` are listed in the order the perks are applied.
+//
+// See [`State::breakdown`]
+#[derive(Clone, Debug, Getters)]
+pub struct PerkBreakdown {
+    #[getter(copy)]
+    base: f64,
+    steps: Vec<PerkStep>,
+    #[getter(copy)]
+    value: f64,
+}
+
+// Random parts of a product's construction.
+// Each construction rolls the amount of units it yields and its rare byproducts,
+// while a critical construction multiplies the units it rolled.
+// Perks are applied on top of the rolled amounts.
+//
+// # Example
+// Pressing lemons yields 1 to 3 lemonades with 10% chance to double them
+// and 5% chance of a seed as a byproduct:
+// ```
+// RandomYield::new((1, 3), vec![(Quantity::Material(SEED, 1), 0.05)], 0.1, 2.0)
+// ```
+#[derive(Clone, Debug, Getters, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RandomYield {
+    #[doc = "Inclusive range of units a single construction yields."]
+    #[getter(copy)]
+    units: (Count, Count),
+
+    #[doc = "Byproducts a construction yields only with the given chance, between 0 and 1."]
+    rare_outputs: Vec<(Quantity, f64)>,
+
+    #[doc = "Chance of a construction being critical, between 0 and 1."]
+    #[doc = "May be modified by perks targeting [`PerkTarget::CritChance`]."]
+    #[getter(copy)]
+    crit_chance: f64,
+
+    #[doc = "Multiplier of the units a critical construction yields."]
+    #[getter(copy)]
+    crit_multiplier: f64,
+}
+
+impl Default for RandomYield {
+    fn default() -> Self {
+        Self {
+            units: (1, 1),
+            rare_outputs: Vec::new(),
+            crit_chance: 0.0,
+            crit_multiplier: 2.0,
+        }
+    }
+}
+
+impl RandomYield {
+    pub fn new(
+        units: (Count, Count),
+        rare_outputs: Vec<(Quantity, f64)>,
+        crit_chance: f64,
+        crit_multiplier: f64,
+    ) -> Self {
+        Self {
+            units,
+            rare_outputs,
+            crit_chance,
+            crit_multiplier,
+        }
+    }
+
+    fn min_units(&self) -> Count {
+        self.units.0
+    }
+
+    fn max_units(&self) -> Count {
+        self.units.1.max(self.units.0)
+    }
+
+    fn crit_units(&self, units: Count) -> Count {
+        (units as f64 * self.crit_multiplier).round() as Count
+    }
+}
+
+// Defines how the stock of a material or a product goes bad over time.
+// Perks targeting the decay modify how fast the stock spoils, see [`Decay::rate`],
+// f.e a refrigerator halving the spoilage of lemons would be:
+// ```
+// Perk::new(..., perk: (PerkTarget::MaterialDecay(LEMON, 0.5), PerkKind::Multiply))
+// ```
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Decay {
+    #[doc = "Fraction of the stock lost every second, between 0 and 1."]
+    Fraction(f64),
+
+    #[doc = "Amount of the stock lost every second."]
+    Amount(f64),
+
+    #[doc = "Seconds after which a batch of the stock goes bad."]
+    #[doc = "Stock is used up oldest batch first."]
+    ShelfLife(f64),
+}
+
+impl Decay {
+    pub fn value(&self) -> f64 {
+        match *self {
+            Decay::Fraction(x) | Decay::Amount(x) | Decay::ShelfLife(x) => x,
+        }
+    }
+
+    // How fast the stock spoils, the value modified by perks.
+    // It's the value itself, except for `ShelfLife` where it's 1 / the shelf life,
+    // so halving the rate doubles the shelf life.
+    pub fn rate(&self) -> f64 {
+        match *self {
+            Decay::Fraction(x) | Decay::Amount(x) => x,
+            Decay::ShelfLife(life) => 1.0 / life,
+        }
+    }
+
+    fn with_rate(&self, rate: f64) -> Decay {
+        match self {
+            Decay::Fraction(_) => Decay::Fraction(rate.clamp(0.0, 1.0)),
+            Decay::Amount(_) => Decay::Amount(rate.max(0.0)),
+            // Stock that doesn't spoil at all never goes bad.
+            Decay::ShelfLife(_) => Decay::ShelfLife(1.0 / rate.max(0.0)),
+        }
+    }
+}
+
+// Keeps track of the decay of a stock between updates.
+#[derive(Clone, Debug, Default)]
+struct Spoilage {
+    carried: f64,                         // fraction of a unit decayed so far
+    batches: VecDeque<(Count, Duration)>, // amounts and ages of the stock, oldest first
+}
+
+impl Spoilage {
+    // Returns the amount of the `count` stock lost during `delta`.
+    fn step(&mut self, decay: Decay, count: Count, delta: Duration) -> Count {
+        let secs = delta.as_secs_f64();
+
+        match decay {
+            Decay::Fraction(fraction) => {
+                let kept = (1.0 - fraction).powf(secs);
+                self.carry(count as f64 * (1.0 - kept), count)
+            }
+            Decay::Amount(amount) => self.carry(amount * secs, count),
+            Decay::ShelfLife(life) => {
+                let life = Duration::try_from_secs_f64(life).unwrap_or(Duration::MAX);
+                self.track(count);
+
+                for batch in self.batches.iter_mut() {
+                    batch.1 += delta;
+                }
+
+                let mut lost = 0;
+                while let Some(&(cnt, age)) = self.batches.front() {
+                    if age < life {
+                        break;
+                    }
+                    lost += cnt;
+                    self.batches.pop_front();
+                }
+
+                lost
+            }
+        }
+    }
+
+    fn carry(&mut self, lost: f64, count: Count) -> Count {
+        self.carried += lost;
+        let whole = self.carried.floor();
+        self.carried -= whole;
+
+        (whole as Count).min(count)
+    }
+
+    // Matches the batches with the current stock. Stock added since
+    // the last update is a new batch, while the used up stock is
+    // taken from the oldest batches.
+    fn track(&mut self, count: Count) {
+        let tracked: Count = self.batches.iter().map(|(cnt, _)| cnt).sum();
+        if count > tracked {
+            self.batches.push_back((count - tracked, Duration::ZERO));
+            return;
+        }
+
+        let mut used = tracked - count;
+        while used > 0 {
+            let batch = self.batches.front_mut().unwrap();
+            let take = used.min(batch.0);
+            batch.0 -= take;
+            used -= take;
+
+            if batch.0 == 0 {
+                self.batches.pop_front();
+            }
+        }
+    }
+}
+
+fn default_demand() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Product {
     #[serde(skip)]
     #[doc = "Current count in stock. Not sold, yet."]
@@ -410,9 +1683,49 @@ pub struct Product {
     #[doc = "Optional price at which the product is sold. If None it will not be sold, and the user may specify it as a material for other products."]
     price: Option<Price>,
 
+    #[doc = "Currency the product is sold for. If None it is sold for money."]
+    #[serde(default)]
+    currency: Option<CurrencyId>,
+
     #[doc = "List of Relations to other quantities that are taken into consideration during construction of the product. See [`RelationKind`]"]
     dependencies: Vec<Relation>,
 
+    #[doc = "Byproducts added on each construction of the product, f.e peel when pressing lemons. Scaled by the number of constructions and the product's perks."]
+    #[serde(default)]
+    outputs: Vec<Quantity>,
+
+    #[doc = "Random yield, rare byproducts and critical constructions. By default every construction yields exactly one unit."]
+    #[serde(default)]
+    random: RandomYield,
+
+    #[doc = "Time a single construction takes. If None the product is constructed instantly,"]
+    #[doc = "otherwise constructions are queued, see [`State::construct_product`]."]
+    #[serde(default)]
+    build_time: Option<Timer>,
+
+    #[doc = "How the stock of the product goes bad over time, if at all."]
+    #[serde(default)]
+    decay: Option<Decay>,
+
+    #[serde(skip)]
+    spoilage: Spoilage,
+
+    #[serde(skip)]
+    #[doc = "Amount of stock lost to decay."]
+    decayed: Count,
+
+    #[doc = "Money paid per unit of stock per second, f.e for storage."]
+    #[serde(default)]
+    upkeep: Price,
+
+    #[doc = "Multiplier of the interest towards the product."]
+    #[serde(default = "default_demand")]
+    demand: f64,
+
+    #[doc = "Tags grouping the product with other products, see [`PerkScope::Tag`]."]
+    #[serde(default)]
+    tags: Vec<String>,
+
     #[doc = "List of perk indices that may be applied to the product"]
     perks: Vec<PerkId>,
 
@@ -437,18 +1750,80 @@ impl Product {
             name,
             count: 0,
             price,
+            currency: None,
             sold: 0,
             dependencies,
+            outputs: Vec::new(),
+            random: RandomYield::default(),
+            build_time: None,
+            decay: None,
+            spoilage: Spoilage::default(),
+            decayed: 0,
+            upkeep: 0.0,
+            demand: 1.0,
+            tags: Vec::new(),
             perks,
             unlocks,
             active: unlocked,
         }
     }
 
+    // Adds byproducts yielded on each construction of the product.
+    pub fn with_outputs(mut self, outputs: Vec<Quantity>) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    // Makes the stock of the product go bad over time.
+    pub fn with_decay(mut self, decay: Decay) -> Self {
+        self.decay = Some(decay);
+        self
+    }
+
+    // Makes the constructions of the product take time.
+    pub fn with_build_time(mut self, build_time: Duration) -> Self {
+        self.build_time = Some(Timer::new(build_time));
+        self
+    }
+
+    // Makes each unit of the product's stock cost `upkeep` money per second.
+    pub fn with_upkeep(mut self, upkeep: Price) -> Self {
+        self.upkeep = upkeep;
+        self
+    }
+
+    // Scales the interest towards the product.
+    pub fn with_demand(mut self, demand: f64) -> Self {
+        self.demand = demand;
+        self
+    }
+
+    // Tags the product so perks may target it along with other products of the same tag.
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    // Makes the constructions of the product random.
+    pub fn with_random(mut self, random: RandomYield) -> Self {
+        self.random = random;
+        self
+    }
+
+    // Sells the product for one of the declared currencies instead of money.
+    pub fn with_currency(mut self, currency: CurrencyId) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    pub fn currency(&self) -> Option<CurrencyId> {
+        self.currency
+    }
+
     pub fn count(&self) -> Count {
         self.count
     }
@@ -458,13 +1833,31 @@ impl Product {
     }
 
     pub fn produced(&self) -> Count {
-        self.count + self.sold
+        self.count + self.sold + self.decayed
+    }
+
+    pub fn decayed(&self) -> Count {
+        self.decayed
+    }
+
+    // Base decay, see [`State::product_decay`] for the effective one.
+    pub fn decay(&self) -> Option<Decay> {
+        self.decay
+    }
+
+    pub fn demand(&self) -> f64 {
+        self.demand
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
     }
 
     // Interest towards the product. Treated by the library as a percentage.
     // If the interest is above 100% the product will be bought at every tick,
     // assuming all the sell conditions are met - i.e there are no `Sell` dependencies
     // or all the `Sell` dependencies are available.
+    // The interest is scaled by the product's demand.
     pub fn interest(&self) -> f64 {
         match self.price {
             None => 0.0,
@@ -478,7 +1871,7 @@ impl Product {
                     price
                 };
 
-                init + 0.5 / price + ((self.sold as f64).powf(1.07) / 100.0)
+                (init + 0.5 / price + ((self.sold as f64).powf(1.07) / 100.0)) * self.demand
             }
         }
     }
@@ -534,6 +1927,19 @@ impl Product {
             for m in manufactured_by.iter() {
                 recipe.push_str(&m.as_str(state));
             }
+
+            has_prev = true;
+        }
+
+        if !self.outputs.is_empty() {
+            if has_prev {
+                recipe.push_str("; ");
+            }
+
+            recipe.push_str("Yields: ");
+            for output in self.outputs.iter() {
+                recipe.push_str(&output.as_str(state));
+            }
         }
 
         recipe
@@ -558,6 +1964,22 @@ impl Product {
         &self.dependencies
     }
 
+    pub fn outputs(&self) -> &Vec<Quantity> {
+        &self.outputs
+    }
+
+    pub fn random(&self) -> &RandomYield {
+        &self.random
+    }
+
+    pub fn build_time(&self) -> Option<Duration> {
+        self.build_time.as_ref().map(|timer| timer.duration())
+    }
+
+    pub fn upkeep(&self) -> Price {
+        self.upkeep
+    }
+
     pub(crate) fn perks(&self) -> &Vec<PerkId> {
         &self.perks
     }
@@ -571,6 +1993,10 @@ impl Product {
     }
 }
 
+fn default_batch() -> Count {
+    1
+}
+
 #[derive(Clone, Copy, Deserialize, Serialize)]
 pub enum AutomationKind {
     Buy(ProductMaterialId),
@@ -580,7 +2006,7 @@ pub enum AutomationKind {
 // Automates either construction of a product
 // or buying of a material, depending on `kind`
 // If `timer`
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Automation {
     name: String,
     kind: AutomationKind,
@@ -594,6 +2020,14 @@ pub struct Automation {
     #[doc = "List of quantities that will be consumed when the automation is bought"]
     buy_price: Vec<Quantity>,
 
+    #[doc = "Money paid each time the automation runs. It doesn't run if it can't be paid."]
+    #[serde(default)]
+    upkeep: Price,
+
+    #[doc = "Number of constructions or purchases done per run."]
+    #[serde(default = "default_batch")]
+    batch: Count,
+
     #[serde(skip)]
     paused: bool,
 
@@ -618,12 +2052,26 @@ impl Automation {
             timer,
             condition,
             buy_price,
+            upkeep: 0.0,
+            batch: 1,
             paused: false,
             unlocked: false,
             active: false,
         }
     }
 
+    // Makes each run of the automation cost `upkeep` money.
+    pub fn with_upkeep(mut self, upkeep: Price) -> Self {
+        self.upkeep = upkeep;
+        self
+    }
+
+    // Makes each run of the automation build or buy `batch` times.
+    pub fn with_batch(mut self, batch: Count) -> Self {
+        self.batch = batch;
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -669,6 +2117,14 @@ impl Automation {
         &self.buy_price
     }
 
+    pub fn upkeep(&self) -> Price {
+        self.upkeep
+    }
+
+    pub fn batch(&self) -> Count {
+        self.batch
+    }
+
     pub(crate) fn kind(&self) -> AutomationKind {
         self.kind
     }
@@ -690,10 +2146,28 @@ impl Automation {
     }
 }
 
-// Represents a badge that is won on certain condition.
-// May be used as another story-telling device.
-#[derive(Deserialize, Serialize)]
-pub struct Badge {
+// Explains what the next run of an automation would do.
+//
+// See [`State::automation_report`]
+#[derive(Clone, Debug)]
+pub enum AutomationReport {
+    #[doc = "The automation's conditions are not met yet."]
+    Locked,
+
+    #[doc = "The automation is unlocked but not bought."]
+    NotBought,
+
+    #[doc = "The automation is paused by the player."]
+    Paused,
+
+    Build(BuildReport),
+    Buy(BuyReport),
+}
+
+// Represents a badge that is won on certain condition.
+// May be used as another story-telling device.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Badge {
     name: String,
     #[serde(alias = "desc")]
     description: String, // We let the designer write his custom description
@@ -737,13 +2211,15 @@ impl Badge {
 }
 
 // Defines the objectives that the player must achieve in order to win the game
+// An empty objective is met once all the stages are completed, a game without
+// an objective and stages is never won and goes on for as long as it's played.
 //
 // # Example
 // The player will win when he has 1 million amount of money:
 // ```
 // Objective::new(vec![Quantity::Money(1000000.)])
 // ```
-#[derive(Default, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct Objective(Vec<Condition>);
 
 impl Objective {
@@ -756,6 +2232,443 @@ impl Objective {
     }
 }
 
+// Change to the game made when a stage is completed.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum StageEffect {
+    #[doc = "Unlocks a product along with its dependencies."]
+    UnlockProduct(ProductId),
+
+    UnlockMaterial(ProductMaterialId),
+
+    #[doc = "Sets the demand of a product, see [`Product::interest`]."]
+    Demand(ProductId, f64),
+
+    SetMoney(Price),
+    SetMaterial(ProductMaterialId, Count),
+    SetProduct(ProductId, Count),
+
+    #[doc = "Reveals a section of the UI named by the game, see [`State::revealed`]."]
+    Reveal(String),
+}
+
+// A chapter of the game with its own objective.
+// Stages are completed in order and each one applies its
+// effects once its objective is met.
+//
+// See [`State::stage`]
+#[derive(Deserialize, Serialize)]
+pub struct Stage {
+    name: String,
+    objective: Objective,
+
+    #[serde(default)]
+    effects: Vec<StageEffect>,
+}
+
+impl Stage {
+    pub fn new(name: String, objective: Objective, effects: Vec<StageEffect>) -> Self {
+        Self {
+            name,
+            objective,
+            effects,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn objective(&self) -> &Objective {
+        &self.objective
+    }
+
+    pub fn effects(&self) -> &[StageEffect] {
+        &self.effects
+    }
+}
+
+// Part of the progress reset by a prestige.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ResetScope {
+    Money,
+
+    #[doc = "All the currencies except the prestige one."]
+    Currencies,
+
+    #[doc = "Materials' counts, amounts bought and unlocks."]
+    Materials,
+
+    #[doc = "Products' counts, amounts sold, unlocks and the production queue."]
+    Products,
+
+    #[doc = "All the perks which are not persistent."]
+    Perks,
+
+    Automations,
+    Badges,
+    Stages,
+}
+
+fn default_reset() -> Vec<ResetScope> {
+    vec![
+        ResetScope::Money,
+        ResetScope::Currencies,
+        ResetScope::Materials,
+        ResetScope::Products,
+        ResetScope::Perks,
+        ResetScope::Automations,
+        ResetScope::Stages,
+    ]
+}
+
+// A reset layer. Prestiging resets a part of the progress in exchange for
+// a currency which is never reset. The currency is usually spent on
+// persistent perks, which act as permanent upgrades.
+//
+// The reward is an expression of the stats of the current run - `money`,
+// `sold` and `produced`(summed over all products) and `time` in seconds,
+// of the lifetime stats - `total_sold`, `total_produced` and `total_time`,
+// including the current run, and of the number of `prestiges` done so far.
+// It is rounded down.
+//
+// # Example
+// ```
+// Prestige::new(PRESTIGE_POINTS, "sqrt(total_sold / 100)".to_string(), vec![Condition::Money(1e6)])
+// ```
+#[derive(Deserialize, Serialize)]
+pub struct Prestige {
+    currency: CurrencyId,
+    reward: Expr,
+
+    #[doc = "Conditions to be met in order to prestige."]
+    #[serde(default)]
+    condition: Vec<Condition>,
+
+    #[doc = "Parts of the progress reset. Everything but the badges by default."]
+    #[serde(default = "default_reset")]
+    reset: Vec<ResetScope>,
+}
+
+impl Prestige {
+    pub fn new(currency: CurrencyId, reward: Expr, condition: Vec<Condition>) -> Self {
+        Self {
+            currency,
+            reward,
+            condition,
+            reset: default_reset(),
+        }
+    }
+
+    pub fn with_reset(mut self, reset: Vec<ResetScope>) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    pub fn currency(&self) -> CurrencyId {
+        self.currency
+    }
+
+    pub fn reward(&self) -> &str {
+        &self.reward
+    }
+
+    pub fn reset(&self) -> &[ResetScope] {
+        &self.reset
+    }
+
+    pub(crate) fn condition(&self) -> &Vec<Condition> {
+        &self.condition
+    }
+}
+
+// Modified rule of the game applied during a challenge.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ChallengeRule {
+    #[doc = "Automations don't run."]
+    NoAutomations,
+
+    #[doc = "Multiplies the `x` the prices of all the materials grow by, see [`ProductMaterial::price_at`]."]
+    #[doc = "F.e 2.0 squares a growth factor rather than doubling it, as `growth ^ (2x / 10)`"]
+    #[doc = "is `(growth ^ (x / 10)) ^ 2`."]
+    MaterialGrowth(f64),
+
+    #[doc = "Multiplies the demand of all the products."]
+    Demand(f64),
+}
+
+// A run of the game with modified rules and its own goal.
+// Starting a challenge resets the progress like a prestige does and
+// applies the rules on top of the loaded definition. Meeting the goal
+// completes the challenge, which resets the progress once again and
+// activates the reward perks for good.
+//
+// # Example
+// A challenge with no automations rewarding perk 3:
+// ```
+// Challenge::new(
+//     "Handmade".to_string(),
+//     vec![ChallengeRule::NoAutomations],
+//     Objective::new(vec![Condition::Money(1e4)]),
+//     vec![3],
+// )
+// ```
+#[derive(Deserialize, Serialize)]
+pub struct Challenge {
+    name: String,
+    rules: Vec<ChallengeRule>,
+    goal: Objective,
+
+    #[doc = "Perks activated once the challenge is completed. They stay active through resets."]
+    #[serde(default)]
+    reward: Vec<PerkId>,
+
+    #[serde(default)]
+    completed: bool,
+}
+
+impl Challenge {
+    pub fn new(
+        name: String,
+        rules: Vec<ChallengeRule>,
+        goal: Objective,
+        reward: Vec<PerkId>,
+    ) -> Self {
+        Self {
+            name,
+            rules,
+            goal,
+            reward,
+            completed: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn rules(&self) -> &[ChallengeRule] {
+        &self.rules
+    }
+
+    pub fn goal(&self) -> &Objective {
+        &self.goal
+    }
+
+    pub fn reward(&self) -> &[PerkId] {
+        &self.reward
+    }
+
+    pub fn completed(&self) -> bool {
+        self.completed
+    }
+}
+
+// Stats kept across prestige resets. They include the current run
+// only once it is reset.
+#[derive(Copy, Clone, Debug, Default, Getters, Deserialize, Serialize)]
+pub struct Lifetime {
+    #[getter(copy)]
+    prestiges: Count,
+
+    #[getter(copy)]
+    sold: Count,
+
+    #[getter(copy)]
+    produced: Count,
+
+    #[getter(copy)]
+    time: Duration,
+}
+
+// Progress at the start of the game, restored by prestige resets.
+#[derive(Clone)]
+struct Initial {
+    money: f64,
+    currencies: Vec<Currency>,
+    materials: Vec<ProductMaterial>,
+    products: Vec<Product>,
+    perks: Vec<Perk>,
+    automations: Vec<Automation>,
+    badges: Vec<Badge>,
+    objective: Objective,
+    post_win: PostWin,
+}
+
+// A way to lose the game, f.e running out of time or of a key material.
+// The game is lost as soon as all of the conditions are met.
+// Lose conditions without any conditions are never met.
+//
+// # Example
+// The player will lose after an hour of playing:
+// ```
+// LoseCondition::new(
+//     "Out of time".to_string(),
+//     vec![Condition::Compare(Stat::TimePlayed, Comparison::Ge, 3600.0)],
+// )
+// ```
+#[derive(Deserialize, Serialize)]
+pub struct LoseCondition {
+    name: String,
+    condition: Vec<Condition>,
+}
+
+impl LoseCondition {
+    pub fn new(name: String, condition: Vec<Condition>) -> Self {
+        Self { name, condition }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn condition(&self) -> &Vec<Condition> {
+        &self.condition
+    }
+}
+
+// Why the game was lost.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LoseReason {
+    #[doc = "The money ran out and the bankruptcy policy is [`Bankruptcy::Lose`]."]
+    Bankruptcy,
+
+    #[doc = "A lose condition of the [`State`] was met."]
+    Condition(LoseConditionId),
+}
+
+// State of the game as a whole.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Outcome {
+    #[default]
+    InProgress,
+
+    #[doc = "The objective was met. Whether the game is still updated depends on [`PostWin`]."]
+    Won,
+
+    #[doc = "The game is over and is no longer updated."]
+    Lost(LoseReason),
+}
+
+// Defines what happens once the objective is met.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub enum PostWin {
+    #[doc = "The game is over and is no longer updated."]
+    #[default]
+    Stop,
+
+    #[doc = "The game goes on as before."]
+    Continue,
+
+    #[doc = "The game goes on with a new objective. Once it is met the game stops."]
+    NewObjective(Objective),
+}
+
+// Snapshot of the game taken when an objective is met.
+//
+// See [`State::wins`]
+#[derive(Clone, Debug, Getters, Deserialize, Serialize)]
+pub struct WinRecord {
+    #[doc = "Time played until the objective was met."]
+    #[getter(copy)]
+    time: Duration,
+
+    #[getter(copy)]
+    money: f64,
+
+    #[doc = "Produced amount of each product."]
+    produced: Vec<Count>,
+
+    #[doc = "Sold amount of each product."]
+    sold: Vec<Count>,
+
+    #[doc = "Bought amount of each material."]
+    bought: Vec<Count>,
+}
+
+// Something that happened during the last [`State::update`]
+// the game may want to let the player know about.
+//
+// See [`State::events`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    MaterialDecayed(ProductMaterialId, Count),
+    ProductDecayed(ProductId, Count),
+
+    #[doc = "The money ran out while paying the upkeep."]
+    Bankrupt,
+
+    StageCompleted(StageId),
+
+    #[doc = "An objective was met, see [`State::wins`]."]
+    Won,
+
+    #[doc = "Amount of the prestige currency awarded by a prestige reset."]
+    Prestige(Price),
+
+    ChallengeCompleted(ChallengeId),
+
+    #[doc = "Amount of a material sold off to pay the debt, see [`Bankruptcy::SellMaterials`]."]
+    MaterialSoldOff(ProductMaterialId, Count),
+}
+
+// Defines what happens when the money runs out while paying the upkeep.
+// The policy applies on every update the upkeep leaves the money below zero.
+// Money is allowed to go below zero and while it is automations
+// don't run, no matter the policy.
+//
+// See [`State::upkeep`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum Bankruptcy {
+    #[doc = "Automations stop running until the debt is paid off."]
+    #[default]
+    PauseAutomations,
+
+    #[doc = "Bought units of the materials bought with money are sold off, last one first,"]
+    #[doc = "until the debt is paid off."]
+    SellMaterials,
+
+    #[doc = "The game is lost."]
+    Lose,
+}
+
+// A construction of a product with a build time waiting in the production queue.
+// The dependencies are consumed when the order is queued and the product
+// is yielded once the order is worked on for the product's build time.
+//
+// See [`State::construct_product`]
+#[derive(Clone, Debug, Getters)]
+pub struct Order {
+    #[getter(copy)]
+    product: ProductId,
+
+    #[getter(copy)]
+    duration: Duration,
+
+    #[doc = "Time the order was worked on so far."]
+    #[getter(copy)]
+    elapsed: Duration,
+}
+
+impl Order {
+    // Part of the order done, between 0 and 1.
+    pub fn progress(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+
+        (self.elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.duration.saturating_sub(self.elapsed)
+    }
+
+    // Whether the order is being worked on or is waiting for a free slot.
+    pub fn started(&self) -> bool {
+        !self.elapsed.is_zero()
+    }
+}
+
 // Defines the rules of the game - the objectives,
 // all the product materials, products, badges, perks and automations.
 // All the types that have *Id name(f.e PerkId) are indexing into
@@ -766,16 +2679,83 @@ pub struct State {
     #[serde(alias = "init_money")]
     money: f64,
 
+    #[doc = "Currencies used in addition to money."]
+    #[serde(default)]
+    currencies: Vec<Currency>,
+
+    #[doc = "Objective met after all the stages are completed."]
+    #[serde(default)]
     objective: Objective,
+
+    #[doc = "Chapters of the game, completed in order."]
+    #[serde(default)]
+    stages: Vec<Stage>,
+
     materials: Vec<ProductMaterial>,
     products: Vec<Product>,
     badges: Vec<Badge>,
     perks: Vec<Perk>,
     automations: Vec<Automation>,
 
+    #[doc = "What happens when the money runs out while paying the upkeep."]
+    #[getter(copy)]
+    #[serde(default)]
+    bankruptcy: Bankruptcy,
+
+    #[doc = "What happens once the objective is met."]
+    #[serde(default)]
+    post_win: PostWin,
+
+    #[doc = "Records of the objectives met, in order."]
+    #[serde(default)]
+    wins: Vec<WinRecord>,
+
+    #[getter(skip)]
+    #[serde(default)]
+    prestige: Option<Prestige>,
+
+    #[doc = "Stats kept across prestige resets."]
+    #[getter(copy)]
+    #[serde(default)]
+    lifetime: Lifetime,
+
+    #[doc = "Runs with modified rules, see [`State::start_challenge`]."]
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+
+    #[doc = "Challenge being played, if any."]
+    #[getter(copy)]
+    #[serde(skip)]
+    challenge: Option<ChallengeId>,
+
+    #[getter(skip)]
+    #[serde(skip)]
+    initial: Option<Box<Initial>>,
+
+    #[doc = "Ways to lose the game, checked along with the objective."]
+    #[serde(default)]
+    lose_conditions: Vec<LoseCondition>,
+
+    #[getter(copy)]
+    #[serde(skip)]
+    outcome: Outcome,
+
+    #[doc = "Index of the current stage. Equals the number of stages once all are completed."]
+    #[getter(copy)]
+    #[serde(skip)]
+    stage: StageId,
+
+    #[getter(skip)]
+    #[serde(skip)]
+    time: Duration,
+
+    #[doc = "Orders of products with build time, in the order they were queued."]
+    #[serde(skip)]
+    queue: Vec<Order>,
+
     #[getter(skip)]
     #[serde(skip)]
-    win: bool,
+    events: Vec<Event>,
 }
 
 impl State {
@@ -790,13 +2770,79 @@ impl State {
     ) -> Self {
         Self {
             money: init_money,
+            currencies: Vec::new(),
             objective,
+            stages: Vec::new(),
             materials,
             products,
             badges,
             perks,
             automations,
-            win: false,
+            bankruptcy: Bankruptcy::default(),
+            post_win: PostWin::default(),
+            wins: Vec::new(),
+            prestige: None,
+            lifetime: Lifetime::default(),
+            challenges: Vec::new(),
+            challenge: None,
+            initial: None,
+            lose_conditions: Vec::new(),
+            outcome: Outcome::InProgress,
+            stage: 0,
+            time: Duration::ZERO,
+            queue: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    // Declares the currencies used in addition to money.
+    pub fn with_currencies(mut self, currencies: Vec<Currency>) -> Self {
+        self.currencies = currencies;
+        self
+    }
+
+    pub fn with_bankruptcy(mut self, bankruptcy: Bankruptcy) -> Self {
+        self.bankruptcy = bankruptcy;
+        self
+    }
+
+    pub fn with_lose_conditions(mut self, lose_conditions: Vec<LoseCondition>) -> Self {
+        self.lose_conditions = lose_conditions;
+        self
+    }
+
+    pub fn with_post_win(mut self, post_win: PostWin) -> Self {
+        self.post_win = post_win;
+        self
+    }
+
+    pub fn with_prestige(mut self, prestige: Prestige) -> Self {
+        self.prestige = Some(prestige);
+        self
+    }
+
+    pub fn with_challenges(mut self, challenges: Vec<Challenge>) -> Self {
+        self.challenges = challenges;
+        self
+    }
+
+    pub fn with_stages(mut self, stages: Vec<Stage>) -> Self {
+        self.stages = stages;
+        self
+    }
+
+    // Amount available of the given currency, money if None.
+    pub fn balance(&self, currency: Option<CurrencyId>) -> f64 {
+        match currency {
+            Some(id) => self.currencies[id].amount,
+            None => self.money,
+        }
+    }
+
+    fn balance_mut(&mut self, currency: Option<CurrencyId>) -> &mut f64 {
+        match currency {
+            Some(id) => &mut self.currencies[id].amount,
+            None => &mut self.money,
         }
     }
 
@@ -804,38 +2850,272 @@ impl State {
     fn quantity_present_count(&self, q: &Quantity) -> Count {
         match q {
             Quantity::Money(money) => (self.money / *money).floor() as Count,
+            Quantity::Currency(id, amount) => {
+                (self.currencies[*id].amount / *amount).floor() as Count
+            }
             Quantity::Material(id, cnt) => self.materials[*id].count / *cnt,
             Quantity::Product(id, cnt, _) => self.products[*id].count() / *cnt,
         }
     }
 
     #[inline]
-    fn check_condition(&self, cond: &Quantity) -> bool {
+    fn quantity_present(&self, q: &Quantity) -> f64 {
+        match q {
+            Quantity::Money(_) => self.money,
+            Quantity::Currency(id, _) => self.currencies[*id].amount,
+            Quantity::Material(id, _) => self.materials[*id].count as f64,
+            Quantity::Product(id, _, _) => self.products[*id].count() as f64,
+        }
+    }
+
+    // Returns the current value of a stat.
+    pub fn stat(&self, stat: Stat) -> f64 {
+        let flag = |b: bool| if b { 1.0 } else { 0.0 };
+
+        match stat {
+            Stat::Money => self.money,
+            Stat::Currency(id) => self.currencies[id].amount,
+            Stat::Material(id) => self.materials[id].count() as f64,
+            Stat::MaterialBought(id) => self.materials[id].bought() as f64,
+            Stat::MaterialDecayed(id) => self.materials[id].decayed() as f64,
+            Stat::ProductDecayed(id) => self.products[id].decayed() as f64,
+            Stat::Product(id, kind) => match kind {
+                ProductConditionKind::Count => self.products[id].count() as f64,
+                ProductConditionKind::Sold => self.products[id].sold() as f64,
+                ProductConditionKind::Produced => self.products[id].produced() as f64,
+            },
+            Stat::Badge(id) => flag(self.badges[id].unlocked()),
+            Stat::Badges => self.badges.iter().filter(|b| b.unlocked()).count() as f64,
+            Stat::Perk(id) => flag(self.perks[id].active()),
+            Stat::Automation(id) => flag(self.automations[id].active()),
+            Stat::TimePlayed => self.time.as_secs_f64(),
+            Stat::Prestiges => self.lifetime.prestiges as f64,
+            Stat::Challenge(id) => flag(self.challenges[id].completed),
+            Stat::SellPrice(id) => self.sell_price(id).unwrap_or(0.0),
+            Stat::Interest(id) => self.interest(id),
+            Stat::MaterialPrice(id) => self.material_price(id),
+            Stat::MaterialLimit(id) => self
+                .material_limit(id)
+                .map_or(f64::INFINITY, |limit| limit as f64),
+            Stat::AutomationTimer(id) => self
+                .automation_timer(id)
+                .map_or(0.0, |timer| timer.as_secs_f64()),
+            Stat::AutomationBatch(id) => self.automation_batch(id) as f64,
+        }
+    }
+
+    pub fn check_condition(&self, cond: &Condition) -> bool {
         match cond {
-            Quantity::Money(money) => self.money >= *money,
-            Quantity::Material(id, cnt) => self.materials[*id].count >= *cnt,
-            Quantity::Product(id, cnt, product_cond) => {
+            Condition::Money(money) => self.money >= *money,
+            Condition::Currency(id, amount) => self.currencies[*id].amount >= *amount,
+            Condition::Material(id, cnt) => self.materials[*id].count >= *cnt,
+            Condition::Product(id, cnt, product_cond) => {
                 match product_cond.unwrap_or(ProductConditionKind::Produced) {
                     ProductConditionKind::Count => self.products[*id].count() >= *cnt,
                     ProductConditionKind::Sold => self.products[*id].sold() >= *cnt,
                     ProductConditionKind::Produced => self.products[*id].produced() >= *cnt,
                 }
             }
+            Condition::All(conds) => self.check_conditions(conds),
+            Condition::Any(conds) => conds.iter().any(|c| self.check_condition(c)),
+            Condition::Not(conds) => !conds.iter().any(|c| self.check_condition(c)),
+            Condition::Compare(stat, cmp, value) => cmp.compare(self.stat(*stat), *value),
+            Condition::Expr(expr) => parse_condition(expr, self)
+                .map(|cond| self.check_condition(&cond))
+                .unwrap_or(false),
         }
     }
 
-    fn check_conditions(&self, conds: &[Condition]) -> bool {
-        for cond in conds.iter() {
-            if !self.check_condition(cond) {
-                return false;
-            }
+    // Parses all the conditions written as expressions and validates
+    // that all conditions refer to existing objects.
+    // Done by [`crate::loader::load`], so it needs to be called only
+    // when the state is deserialized by other means.
+    pub fn compile_conditions(&mut self) -> Result<()> {
+        macro_rules! compile {
+            ($conds:expr) => {{
+                let mut conds = std::mem::take(&mut $conds);
+                let res = conds.iter_mut().try_for_each(|c| c.compile(self));
+                $conds = conds;
+                res?;
+            }};
         }
 
-        true
-    }
+        compile!(self.objective.0);
+        for id in 0..self.badges.len() {
+            compile!(self.badges[id].condition);
+        }
+        for id in 0..self.perks.len() {
+            compile!(self.perks[id].condition);
+        }
+        for id in 0..self.automations.len() {
+            compile!(self.automations[id].condition);
+        }
+        for id in 0..self.lose_conditions.len() {
+            compile!(self.lose_conditions[id].condition);
+        }
+        for id in 0..self.stages.len() {
+            compile!(self.stages[id].objective.0);
+        }
+        for id in 0..self.challenges.len() {
+            compile!(self.challenges[id].goal.0);
+        }
 
-    fn activate_product(&mut self, id: ProductId) {
-        self.products[id].activate();
+        let mut post_win = std::mem::take(&mut self.post_win);
+        let res = match &mut post_win {
+            PostWin::NewObjective(objective) => {
+                objective.0.iter_mut().try_for_each(|c| c.compile(self))
+            }
+            _ => Ok(()),
+        };
+        self.post_win = post_win;
+        res?;
+
+        let mut prestige = self.prestige.take();
+        let res = match &mut prestige {
+            Some(prestige) => prestige
+                .condition
+                .iter_mut()
+                .try_for_each(|c| c.compile(self)),
+            None => Ok(()),
+        };
+        self.prestige = prestige;
+        res?;
+
+        if let Some(prestige) = &self.prestige {
+            if prestige.currency >= self.currencies.len() {
+                return Err(anyhow!(
+                    "prestige refers to currency {}, but there are only {} of them",
+                    prestige.currency,
+                    self.currencies.len()
+                ));
+            }
+        }
+
+        for perk in self.perks.iter() {
+            let related = perk.requires.iter().chain(perk.excludes.iter());
+            if let Some(id) = related.into_iter().find(|id| **id >= self.perks.len()) {
+                return Err(anyhow!(
+                    "perk '{}' refers to perk {}, but there are only {} of them",
+                    perk.name,
+                    id,
+                    self.perks.len()
+                ));
+            }
+        }
+
+        for challenge in self.challenges.iter() {
+            if let Some(id) = challenge.reward.iter().find(|id| **id >= self.perks.len()) {
+                return Err(anyhow!(
+                    "challenge '{}' rewards perk {}, but there are only {} of them",
+                    challenge.name,
+                    id,
+                    self.perks.len()
+                ));
+            }
+        }
+
+        // Currencies are only referred to by their ids outside of conditions.
+        let currency = |q: &Quantity| match *q {
+            Quantity::Currency(id, _) => Some(id),
+            _ => None,
+        };
+        let mut currencies = Vec::new();
+        for product in self.products.iter() {
+            let quantities = product
+                .dependencies
+                .iter()
+                .map(|rel| rel.quantity())
+                .chain(product.outputs.iter())
+                .chain(product.random.rare_outputs.iter().map(|(output, _)| output));
+            let ids = product
+                .currency
+                .into_iter()
+                .chain(quantities.filter_map(currency));
+            currencies.extend(ids.map(|id| (format!("product '{}'", product.name), id)));
+        }
+        for material in self.materials.iter() {
+            let ids = material.currency.into_iter();
+            currencies.extend(ids.map(|id| (format!("material '{}'", material.name), id)));
+        }
+        for perk in self.perks.iter() {
+            let target = match &perk.perk.0 {
+                PerkTarget::Quantity(q) => currency(q),
+                _ => None,
+            };
+            let ids = perk.buy_price.iter().filter_map(currency).chain(target);
+            currencies.extend(ids.map(|id| (format!("perk '{}'", perk.name), id)));
+        }
+        for automation in self.automations.iter() {
+            let ids = automation.buy_price.iter().filter_map(currency);
+            currencies.extend(ids.map(|id| (format!("automation '{}'", automation.name), id)));
+        }
+        if let Some((owner, id)) = currencies
+            .into_iter()
+            .find(|(_, id)| *id >= self.currencies.len())
+        {
+            return Err(anyhow!(
+                "{} refers to currency {}, but there are only {} of them",
+                owner,
+                id,
+                self.currencies.len()
+            ));
+        }
+
+        for stage in self.stages.iter() {
+            for effect in stage.effects.iter() {
+                let (kind, id, len) = match *effect {
+                    StageEffect::UnlockProduct(id)
+                    | StageEffect::Demand(id, _)
+                    | StageEffect::SetProduct(id, _) => ("product", id, self.products.len()),
+                    StageEffect::UnlockMaterial(id) | StageEffect::SetMaterial(id, _) => {
+                        ("material", id, self.materials.len())
+                    }
+                    StageEffect::SetMoney(_) | StageEffect::Reveal(_) => continue,
+                };
+                if id >= len {
+                    return Err(anyhow!(
+                        "stage '{}' refers to {} {}, but there are only {} of them",
+                        stage.name,
+                        kind,
+                        id,
+                        len
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Records the current progress as the one restored by [`State::prestige`]
+    // and challenges. Done by [`crate::loader::load`] once the definition is loaded,
+    // so it needs to be called only when the state is created by other means.
+    pub fn record_reset_point(&mut self) {
+        self.initial = Some(Box::new(Initial {
+            money: self.money,
+            currencies: self.currencies.clone(),
+            materials: self.materials.clone(),
+            products: self.products.clone(),
+            perks: self.perks.clone(),
+            automations: self.automations.clone(),
+            badges: self.badges.clone(),
+            objective: self.objective.clone(),
+            post_win: self.post_win.clone(),
+        }));
+    }
+
+    fn check_conditions(&self, conds: &[Condition]) -> bool {
+        for cond in conds.iter() {
+            if !self.check_condition(cond) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn activate_product(&mut self, id: ProductId) {
+        self.products[id].activate();
 
         let mut activate_recursive = Vec::new();
 
@@ -847,33 +3127,60 @@ impl State {
             }
         }
 
+        // Byproducts may depend on the product itself, so only
+        // the ones not yet active are activated.
+        for output in self.products[id].outputs().iter() {
+            match *output {
+                Quantity::Material(id, _) => self.materials[id].activate(),
+                Quantity::Product(id, _, _) if !self.products[id].active() => {
+                    activate_recursive.push(id)
+                }
+                _ => {}
+            }
+        }
+
         for id in activate_recursive {
             self.activate_product(id);
         }
     }
 
-    fn apply_perk(&self, id: ProductId, cond: Relation) -> Relation {
-        let mut new_cond = cond;
-        for perk_id in self.products[id].perks().iter() {
-            let perk = &self.perks[*perk_id];
-            if !perk.active {
-                continue;
-            }
-            let perk = perk.perk();
-
-            match &perk.1 {
-                PerkKind::Set => new_cond = new_cond.set(perk.0),
-                PerkKind::Add => new_cond = new_cond.add(perk.0),
-                PerkKind::Subtract => new_cond = new_cond.sub(perk.0),
-                PerkKind::Multiply => new_cond = new_cond.multiply(perk.0),
-                PerkKind::Divide => new_cond = new_cond.divide(perk.0),
-            }
+    // Whether a perk's scope includes the product.
+    fn in_scope(&self, scope: &PerkScope, id: ProductId) -> bool {
+        match scope {
+            PerkScope::Attached => false,
+            PerkScope::AllProducts => true,
+            PerkScope::Tag(tag) => self.products[id].tags.contains(tag),
+            PerkScope::Material(material) => self.products[id]
+                .dependencies
+                .iter()
+                .any(|rel| matches!(rel.quantity(), Quantity::Material(m, _) if m == material)),
+            PerkScope::Automations => false,
+            PerkScope::Automated => self
+                .automations
+                .iter()
+                .any(|automation| matches!(automation.kind(), AutomationKind::Build(product) if product == id)),
         }
+    }
+
+    // Perks that apply to a product, the ones attached to it
+    // followed by the ones whose scope includes it.
+    pub fn product_perks(&self, id: ProductId) -> Vec<PerkId> {
+        let attached = self.products[id].perks();
+        let scoped = (0..self.perks.len())
+            .filter(|perk_id| !attached.contains(perk_id))
+            .filter(|perk_id| self.in_scope(&self.perks[*perk_id].scope, id));
+
+        attached.iter().copied().chain(scoped).collect()
+    }
 
+    pub(crate) fn apply_perk(&self, id: ProductId, cond: Relation) -> Relation {
+        let value = self.breakdown(PerkValue::Quantity(id, cond.quantity)).value;
+        let mut new_cond = cond;
+        new_cond.quantity = cond.quantity.map(|_| value);
         new_cond
     }
 
-    fn apply_product_perks(&self, base_build_count: Count, id: ProductId) -> Count {
+    pub(crate) fn apply_product_perks(&self, base_build_count: Count, id: ProductId) -> Count {
         let cond = Relation::needs(Quantity::Product(id, base_build_count, None));
         let cond = self.apply_perk(id, cond);
 
@@ -886,39 +3193,260 @@ impl State {
         }
     }
 
-    fn build_product_count(&mut self, id: ProductId) -> u64 {
-        // Check conditions
-        let mut prices = Vec::new();
-        let mut max_buy_count = u64::MAX;
-        let mut max_build_count = u64::MAX;
-        for cond in self.products[id].dependencies().iter() {
-            let cond = self.apply_perk(id, *cond);
-            let cnt = self.quantity_present_count(cond.quantity());
-            if cnt == 0 && cond.quantity().quantity() > 0.0 {
-                return 0;
+    // Amount of a byproduct `builds` constructions of a product yield
+    // with the product's active perks applied.
+    pub(crate) fn apply_output_perks(
+        &self,
+        builds: Count,
+        id: ProductId,
+        output: Quantity,
+    ) -> Quantity {
+        *self
+            .apply_perk(id, Relation::needs(output.times(builds)))
+            .quantity()
+    }
+
+    // Amount of a product `builds` constructions yield at the very least,
+    // i.e with the lowest random yield and no critical constructions.
+    pub(crate) fn guaranteed_yield(&self, builds: Count, id: ProductId) -> Count {
+        let units = self.products[id].random().min_units();
+        self.apply_product_perks(builds.saturating_mul(units), id)
+    }
+
+    // Chance of a construction of a product being critical with the active perks applied.
+    // Perks making it undefined or infinite, f.e dividing it by 0, leave no chance at all.
+    pub fn crit_chance(&self, id: ProductId) -> f64 {
+        chance(self.breakdown(PerkValue::CritChance(id)).value)
+    }
+
+    // Price a product is sold at with the perks that apply to it.
+    // None if the product is not sold.
+    pub fn sell_price(&self, id: ProductId) -> Option<Price> {
+        self.products[id].price()?;
+        Some(self.breakdown(PerkValue::SellPrice(id)).value.max(0.0))
+    }
+
+    // Interest towards a product with the perks that apply to it.
+    // It is based on the product's own price, so perks raising the
+    // sell price don't lower the interest. See [`Product::interest`]
+    pub fn interest(&self, id: ProductId) -> f64 {
+        self.breakdown(PerkValue::Interest(id)).value.max(0.0)
+    }
+
+    // Average amount of a product a single construction yields,
+    // taking into account the random yield, critical constructions and the active perks.
+    // Ranges wider than `MAX_ROLLS` units are sampled at the middle of
+    // `MAX_ROLLS` equal parts of the range.
+    pub fn expected_yield(&self, id: ProductId) -> f64 {
+        let random = self.products[id].random();
+        let crit = self.crit_chance(id);
+        let range = (random.max_units() - random.min_units()).saturating_add(1);
+        let samples = range.min(MAX_ROLLS);
+
+        (0..samples)
+            .map(|i| {
+                let offset = (2 * i as u128 + 1) * range as u128 / (2 * samples as u128);
+                let units = random.min_units() + offset as Count;
+                let normal = self.apply_product_perks(units, id) as f64;
+                let critical = self.apply_product_perks(random.crit_units(units), id) as f64;
+                ((1.0 - crit) * normal + crit * critical) / samples as f64
+            })
+            .sum()
+    }
+
+    // Rolls the amount of a product and the rare byproducts `builds` constructions yield.
+    // Only up to `MAX_ROLLS` constructions are rolled and the result is scaled for the rest.
+    fn roll_yield(&self, builds: Count, id: ProductId) -> (Count, Vec<Quantity>) {
+        let random = self.products[id].random();
+        let crit = self.crit_chance(id);
+        let rolls = builds.min(MAX_ROLLS);
+        let scale = |cnt: Count| {
+            if rolls == builds {
+                cnt
+            } else {
+                (cnt as f64 * builds as f64 / rolls as f64).round() as Count
             }
+        };
 
-            match cond.kind() {
-                RelationKind::Consumes => {
-                    max_buy_count = max_buy_count.min(cnt);
-                    prices.push(*cond.quantity());
-                }
-                RelationKind::ManufacturedBy => {
-                    max_build_count = max_build_count.min(cnt);
+        let mut rng = rand::thread_rng();
+        let mut units: Count = 0;
+        for _ in 0..rolls {
+            let rolled = rng.gen_range(random.min_units()..=random.max_units());
+            units = units.saturating_add(if rng.gen_bool(crit) {
+                random.crit_units(rolled)
+            } else {
+                rolled
+            });
+        }
+
+        let rare_outputs = random
+            .rare_outputs()
+            .iter()
+            .filter_map(|(output, chance)| {
+                let chance = self::chance(*chance);
+                let hits = (0..rolls).filter(|_| rng.gen_bool(chance)).count() as Count;
+                if hits == 0 {
+                    return None;
                 }
-                _ => {}
+
+                Some(self.apply_output_perks(scale(hits), id, *output))
+            })
+            .collect();
+
+        (self.apply_product_perks(scale(units), id), rare_outputs)
+    }
+
+    // Returns the recipe of a product with all of its active perks applied.
+    pub fn effective_recipe(&self, id: ProductId) -> Recipe {
+        let entries = self.products[id]
+            .dependencies()
+            .iter()
+            .map(|rel| RecipeEntry {
+                kind: rel.kind(),
+                base: *rel.quantity(),
+                effective: *self.apply_perk(id, *rel).quantity(),
+            })
+            .collect();
+
+        let outputs = self.products[id]
+            .outputs()
+            .iter()
+            .map(|output| (*output, 1.0))
+            .chain(self.products[id].random().rare_outputs().iter().copied())
+            .map(|(output, chance)| RecipeOutput {
+                base: output,
+                effective: self.apply_output_perks(1, id, output),
+                chance,
+            })
+            .collect();
+
+        Recipe {
+            product: id,
+            entries,
+            outputs,
+            base_yield: self.products[id].random().min_units(),
+            effective_yield: self.guaranteed_yield(1, id),
+            expected_yield: self.expected_yield(id),
+            crit_chance: self.crit_chance(id),
+            base_price: self.products[id].price(),
+            sell_price: self.sell_price(id),
+            interest: self.interest(id),
+        }
+    }
+
+    // Explains how many times a product can be constructed right now
+    // and which of its dependencies is limiting the construction.
+    pub fn build_report(&self, id: ProductId) -> BuildReport {
+        let mut dependencies = Vec::new();
+        let mut blocked = None;
+        let mut limiting = None;
+        let mut max_builds = Count::MAX;
+        for (i, rel) in self.products[id].dependencies().iter().enumerate() {
+            let rel = self.apply_perk(id, *rel);
+            let required = *rel.quantity();
+            let max_count = if required.quantity() > 0.0 {
+                self.quantity_present_count(&required)
+            } else {
+                Count::MAX
+            };
+
+            if max_count == 0 && blocked.is_none() {
+                blocked = Some(i);
+            }
+
+            if matches!(
+                rel.kind(),
+                RelationKind::Consumes | RelationKind::ManufacturedBy
+            ) && max_count < max_builds
+            {
+                max_builds = max_count;
+                limiting = Some(i);
             }
+
+            dependencies.push(DependencyReport {
+                kind: rel.kind(),
+                required,
+                available: self.quantity_present(&required),
+                max_count,
+            });
+        }
+
+        if blocked.is_some() {
+            limiting = blocked;
+            max_builds = 0;
+        }
+
+        let outputs = if max_builds > 0 {
+            self.products[id]
+                .outputs()
+                .iter()
+                .map(|output| self.apply_output_perks(max_builds, id, *output))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        BuildReport {
+            product: id,
+            dependencies,
+            limiting,
+            max_builds,
+            max_yield: self.guaranteed_yield(max_builds, id),
+            outputs,
+        }
+    }
+
+    // Explains what the next run of an automation would do.
+    pub fn automation_report(&self, id: AutomationId) -> AutomationReport {
+        let automation = &self.automations[id];
+        if !automation.unlocked() {
+            return AutomationReport::Locked;
+        }
+        if !automation.active() {
+            return AutomationReport::NotBought;
         }
+        if automation.paused() {
+            return AutomationReport::Paused;
+        }
+
+        match automation.kind() {
+            AutomationKind::Build(id) => AutomationReport::Build(self.build_report(id)),
+            AutomationKind::Buy(id) => AutomationReport::Buy(self.buy_report(id)),
+        }
+    }
+
+    // Explains whether a single unit of a material can be bought right now.
+    pub fn buy_report(&self, id: ProductMaterialId) -> BuyReport {
+        let m = &self.materials[id];
+
+        BuyReport {
+            material: id,
+            price: self.material_price(id),
+            currency: m.currency(),
+            money: self.balance(m.currency()),
+            limit_reached: m.count() >= self.material_limit(id).unwrap_or(Count::MAX),
+        }
+    }
 
-        let build_count = max_buy_count.min(max_build_count);
+    // Consumes the dependencies of `build_count` constructions described by the report.
+    fn consume_dependencies(&mut self, report: &BuildReport, build_count: Count) {
+        assert!(build_count <= report.max_builds());
 
         // Buy the product
-        for price in prices {
-            match price {
+        for dep in report.dependencies() {
+            if dep.kind() != RelationKind::Consumes {
+                continue;
+            }
+
+            match dep.required() {
                 Quantity::Money(money) => {
                     assert!(self.money >= build_count as f64 * money);
                     self.money -= build_count as f64 * money;
                 }
+                Quantity::Currency(id, amount) => {
+                    assert!(self.currencies[id].amount >= build_count as f64 * amount);
+                    self.currencies[id].amount -= build_count as f64 * amount;
+                }
                 Quantity::Material(id, cnt) => {
                     assert!(self.materials[id].count >= build_count * cnt);
                     self.materials[id].count -= build_count * cnt;
@@ -929,135 +3457,910 @@ impl State {
                 }
             }
         }
+    }
+
+    // Yields the products and byproducts of `builds` finished constructions of a product.
+    fn complete_builds(&mut self, id: ProductId, builds: Count) {
+        let (count, rare_outputs) = self.roll_yield(builds, id);
+        self.products[id].build(count);
+        self.unlock_products(id);
+
+        let outputs: Vec<_> = self.products[id]
+            .outputs()
+            .iter()
+            .map(|output| self.apply_output_perks(builds, id, *output))
+            .chain(rare_outputs)
+            .collect();
+
+        for output in outputs {
+            if let Some(product_id) = self.add_output(output) {
+                self.unlock_products(product_id);
+            }
+        }
+    }
 
-        // apply product perks so we know how much we can build at a time
-        let built_cnt = self.apply_product_perks(build_count, id);
+    // Number of orders of a product that can be worked on at once, None if unlimited.
+    // Each `ManufacturedBy` dependency provides its capacity for every
+    // required amount of it present.
+    pub fn slots(&self, id: ProductId) -> Option<Count> {
+        self.products[id]
+            .dependencies()
+            .iter()
+            .filter(|rel| rel.kind() == RelationKind::ManufacturedBy)
+            .map(|rel| {
+                let required = *self.apply_perk(id, *rel).quantity();
+                let present = if required.quantity() > 0.0 {
+                    self.quantity_present_count(&required)
+                } else {
+                    Count::MAX
+                };
 
-        built_cnt
+                present.saturating_mul(rel.capacity().unwrap_or(1))
+            })
+            .min()
     }
 
-    fn sell_product(&mut self, id: ProductId) {
-        let product = &self.products[id];
+    // Shows how the active perks modify a value, step by step.
+    //
+    // Perks are stacked in a fixed order no matter the order they're listed in:
+    // first the ones setting the value, then the additive ones and lastly the
+    // multiplicative ones. Within each of these the perks with a lower `priority`
+    // are applied first and ties are broken by the perk ids.
+    pub fn breakdown(&self, value: PerkValue) -> PerkBreakdown {
+        let (base, candidates) = match value {
+            PerkValue::Quantity(id, q) => (q.quantity(), self.product_perks(id)),
+            PerkValue::CritChance(id) => (
+                self.products[id].random().crit_chance(),
+                self.product_perks(id),
+            ),
+            PerkValue::SellPrice(id) => (
+                self.products[id].price().unwrap_or(0.0),
+                self.product_perks(id),
+            ),
+            PerkValue::Interest(id) => (
+                self.products[id].interest() * self.challenge_demand(),
+                self.product_perks(id),
+            ),
+            _ => {
+                let base = match value {
+                    PerkValue::Regeneration(id) => self.materials[id].regeneration,
+                    PerkValue::MaterialDecay(id) => {
+                        self.materials[id].decay.map_or(0.0, |decay| decay.rate())
+                    }
+                    PerkValue::ProductDecay(id) => {
+                        self.products[id].decay.map_or(0.0, |decay| decay.rate())
+                    }
+                    PerkValue::MaterialLimit(id) => self.materials[id]
+                        .limit()
+                        .map_or(f64::INFINITY, |limit| limit as f64),
+                    PerkValue::MaterialGrowth(id) => self.materials[id].growth_scale,
+                    PerkValue::AutomationTimer(id) => self.automations[id]
+                        .timer
+                        .as_ref()
+                        .map_or(0.0, |timer| timer.duration().as_secs_f64()),
+                    PerkValue::AutomationBatch(id) => self.automations[id].batch as f64,
+                    _ => unreachable!(),
+                };
+                (base, (0..self.perks.len()).collect())
+            }
+        };
+        // Counts stay whole after every step, just like the quantities they're applied to.
+        let whole = matches!(
+            value,
+            PerkValue::Quantity(_, Quantity::Material(_, _) | Quantity::Product(_, _, _))
+        );
+
+        let mut perks: Vec<_> = candidates
+            .into_iter()
+            .filter(|id| self.perks[*id].active())
+            .collect();
+        perks.sort_by_key(|id| {
+            (
+                self.perks[*id].perk.1.stage(),
+                self.perks[*id].priority,
+                *id,
+            )
+        });
+
+        let mut steps = Vec::new();
+        let mut current = base;
+        for id in perks {
+            let perk = &self.perks[id];
+            let by = match self.perk_modifier(value, perk) {
+                Some(by) => by,
+                None => continue,
+            };
 
-        if product.price().is_none() || product.interest() == 0.0 || product.count() == 0 {
-            return;
+            let kind = perk.perk.1;
+            let mut after = kind.apply(current, by);
+            if whole {
+                after = (after as Count) as f64;
+            }
+            steps.push(PerkStep {
+                perk: id,
+                kind,
+                by,
+                before: current,
+                after,
+            });
+            current = after;
         }
 
-        let interest = product.interest().min(1.);
-        let mut rng = rand::thread_rng();
-        let sold = if rng.gen_bool(interest / 100.0) { 1 } else { 0 };
-        if sold == 0 {
-            return;
+        PerkBreakdown {
+            base,
+            steps,
+            value: current,
         }
+    }
 
-        let mut sell_multiplier: Option<Count> = None;
-        for dep in product.dependencies().iter() {
-            if dep.kind() != RelationKind::SoldBy {
-                continue;
+    // Value an active perk modifies the given value by, None if the perk doesn't target it.
+    fn perk_modifier(&self, value: PerkValue, perk: &Perk) -> Option<f64> {
+        let (target, _) = perk.effect();
+        match (value, target) {
+            (PerkValue::Quantity(id, q), PerkTarget::Quantity(by)) => {
+                // A scoped perk's product stands for whichever product it's applied to.
+                let by = match by {
+                    Quantity::Product(_, cnt, kind) if perk.scope != PerkScope::Attached => {
+                        Quantity::Product(id, cnt, kind)
+                    }
+                    by => by,
+                };
+                q.same_asset(&by).then(|| by.quantity())
             }
-            let cnt = self.quantity_present_count(dep.quantity());
-            if cnt == 0 {
-                continue;
+            (PerkValue::CritChance(_), PerkTarget::CritChance(by))
+            | (PerkValue::SellPrice(_), PerkTarget::SellPrice(by))
+            | (PerkValue::Interest(_), PerkTarget::Interest(by)) => Some(by),
+            (PerkValue::Regeneration(id), PerkTarget::Regeneration(material, by))
+            | (PerkValue::MaterialDecay(id), PerkTarget::MaterialDecay(material, by))
+            | (PerkValue::MaterialLimit(id), PerkTarget::MaterialLimit(material, by))
+            | (PerkValue::MaterialGrowth(id), PerkTarget::MaterialGrowth(material, by))
+                if id == material =>
+            {
+                Some(by)
             }
-
-            sell_multiplier = match sell_multiplier {
-                Some(mult) => Some(mult.min(cnt)),
-                None => Some(cnt),
-            };
+            (PerkValue::ProductDecay(id), PerkTarget::ProductDecay(product, by))
+                if id == product =>
+            {
+                Some(by)
+            }
+            // Perks scoped to all automations apply no matter which automation they name.
+            (PerkValue::AutomationTimer(id), PerkTarget::AutomationTimer(automation, by))
+            | (PerkValue::AutomationBatch(id), PerkTarget::AutomationBatch(automation, by))
+                if id == automation || perk.scope == PerkScope::Automations =>
+            {
+                Some(by)
+            }
+            _ => None,
         }
-        let sold = (sold * sell_multiplier.unwrap_or(1)).min(product.count());
+    }
 
-        let product = &mut self.products[id];
-        product.sell(sold);
+    // Limit of a material with the active perks applied. None if the material has no limit.
+    pub fn material_limit(&self, id: ProductMaterialId) -> Option<Count> {
+        self.materials[id].limit()?;
+        let limit = self.breakdown(PerkValue::MaterialLimit(id)).value;
 
-        self.money += product.price().unwrap() * sold as f64;
+        Some(limit.max(0.0).round() as Count)
     }
 
-    pub fn construct_product(&mut self, id: ProductId) {
-        let count = self.build_product_count(id);
+    // Multiplier of `x` in the growth of a material's price with the active perks applied.
+    // 1 unless a challenge or a perk changes it. See [`ProductMaterial::price_at`]
+    pub fn material_growth(&self, id: ProductMaterialId) -> f64 {
+        self.breakdown(PerkValue::MaterialGrowth(id)).value.max(0.0)
+    }
 
-        if count == 0 {
-            return;
-        }
+    // Price of the next unit of a material with the active perks applied.
+    pub fn material_price(&self, id: ProductMaterialId) -> Price {
+        self.material_price_at(id, self.materials[id].bought())
+    }
 
-        self.products[id].build(count);
+    // Price of a material after `bought` amount of it was bought with the active perks applied.
+    pub fn material_price_at(&self, id: ProductMaterialId, bought: Count) -> Price {
+        self.materials[id].price_scaled(bought, self.material_growth(id))
+    }
 
-        let mut products_to_activate = Vec::new();
-        for (unlock_id, cnt) in self.products[id].unlocks() {
-            if self.products[id].count > *cnt {
-                products_to_activate.push(*unlock_id);
-            }
-        }
+    // Total price of buying `cnt` more units of a material with the active perks applied.
+    // None if it's too costly to compute, see [`ProductMaterial::cost_scaled`].
+    pub fn material_cost(&self, id: ProductMaterialId, cnt: Count) -> Option<Price> {
+        let m = &self.materials[id];
+        m.cost_scaled(m.bought(), cnt, self.material_growth(id))
+    }
 
-        for id in products_to_activate {
-            self.activate_product(id);
-        }
+    // Refund for selling a single unit of a material right now.
+    // Perks don't change it, as it's based on the price the unit was paid,
+    // see [`ProductMaterial::refund`].
+    pub fn material_refund(&self, id: ProductMaterialId) -> Price {
+        self.materials[id].refund()
     }
 
-    pub fn update(&mut self, delta: Duration) {
-        // Sell available goods
-        for id in 0..self.products.len() {
-            if !self.products[id].active() {
-                continue;
+    // Time between the runs of an automation with the active perks applied.
+    // None if the automation runs on every update.
+    pub fn automation_timer(&self, id: AutomationId) -> Option<Duration> {
+        self.automations[id].timer.as_ref()?;
+        let secs = self.breakdown(PerkValue::AutomationTimer(id)).value;
+
+        // A timer too long to represent never runs out.
+        Some(Duration::try_from_secs_f64(secs.max(0.0)).unwrap_or(Duration::MAX))
+    }
+
+    // Number of constructions or purchases an automation does per run with the active perks applied.
+    pub fn automation_batch(&self, id: AutomationId) -> Count {
+        let batch = self.breakdown(PerkValue::AutomationBatch(id)).value;
+
+        batch.max(0.0).round() as Count
+    }
+
+    // Regeneration rate of a material per second with the active perks applied.
+    pub fn regeneration(&self, id: ProductMaterialId) -> f64 {
+        self.breakdown(PerkValue::Regeneration(id)).value.max(0.0)
+    }
+
+    // Decay of a material with the active perks applied.
+    pub fn material_decay(&self, id: ProductMaterialId) -> Option<Decay> {
+        let decay = self.materials[id].decay?;
+        Some(decay.with_rate(self.breakdown(PerkValue::MaterialDecay(id)).value))
+    }
+
+    // Decay of a product with the active perks applied.
+    pub fn product_decay(&self, id: ProductId) -> Option<Decay> {
+        let decay = self.products[id].decay?;
+        Some(decay.with_rate(self.breakdown(PerkValue::ProductDecay(id)).value))
+    }
+
+    // Removes the stock that went bad during `delta`.
+    fn decay(&mut self, delta: Duration) {
+        for id in 0..self.materials.len() {
+            let decay = match self.material_decay(id) {
+                Some(decay) => decay,
+                None => continue,
+            };
+
+            let m = &mut self.materials[id];
+            let lost = m.spoilage.step(decay, m.count, delta);
+            if lost > 0 {
+                m.count -= lost;
+                m.decayed += lost;
+                self.events.push(Event::MaterialDecayed(id, lost));
             }
+        }
 
-            self.sell_product(id);
+        for id in 0..self.products.len() {
+            let decay = match self.product_decay(id) {
+                Some(decay) => decay,
+                None => continue,
+            };
+
+            let product = &mut self.products[id];
+            let lost = product.spoilage.step(decay, product.count, delta);
+            if lost > 0 {
+                product.count -= lost;
+                product.decayed += lost;
+                self.events.push(Event::ProductDecayed(id, lost));
+            }
         }
+    }
 
-        // Automated products construction
-        let mut products_to_builds = Vec::new();
-        let mut materials_to_buy = Vec::new();
-        for automation in self.automations.iter_mut() {
-            if !automation.active() || automation.paused() {
+    // Events that happened during the last update.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    // Regenerates the active materials up to their limit.
+    // The regenerated amount is proportional to the elapsed time and
+    // fractions of a unit are carried over, so a single large `delta`,
+    // f.e when catching up on offline progress, yields the same amount as many small ones.
+    fn regenerate(&mut self, delta: Duration) {
+        for id in 0..self.materials.len() {
+            let rate = self.regeneration(id);
+            let limit = self.material_limit(id).unwrap_or(Count::MAX);
+            let m = &mut self.materials[id];
+            if !m.active || rate == 0.0 {
                 continue;
             }
-            let run = match automation.timer() {
-                Some(timer) => {
-                    if timer.tick(delta) {
-                        true
-                    } else {
-                        false
-                    }
-                }
-                None => true,
-            };
 
-            if !run {
+            if m.count >= limit {
+                m.regenerated = 0.0;
                 continue;
             }
 
-            match automation.kind() {
-                AutomationKind::Buy(id) => materials_to_buy.push(id),
-                AutomationKind::Build(id) => products_to_builds.push(id),
-            }
-        }
-        for id in products_to_builds {
-            self.construct_product(id);
+            m.regenerated += rate * delta.as_secs_f64();
+            let gained = m.regenerated.floor();
+            m.regenerated -= gained;
+            m.count = m.count.saturating_add(gained as Count).min(limit);
         }
+    }
 
-        for id in materials_to_buy {
-            self.buy_material(id, 1);
+    // The stage being played, None once all the stages are completed.
+    pub fn current_stage(&self) -> Option<&Stage> {
+        self.stages.get(self.stage)
+    }
+
+    // Part of the current stage's objective conditions which are met, between 0 and 1.
+    pub fn stage_progress(&self) -> f64 {
+        let conds = match self.current_stage() {
+            Some(stage) => stage.objective.win_condition(),
+            None => return 1.0,
+        };
+        if conds.is_empty() {
+            return 1.0;
         }
 
-        self.win = self.check_conditions(&self.objective.0);
+        let met = conds.iter().filter(|c| self.check_condition(c)).count();
+        met as f64 / conds.len() as f64
+    }
 
-        if self.win {
-            return;
-        }
+    // Whether a UI section was revealed by a completed stage.
+    pub fn revealed(&self, section: &str) -> bool {
+        self.stages[..self.stage]
+            .iter()
+            .flat_map(|stage| stage.effects.iter())
+            .any(|effect| matches!(effect, StageEffect::Reveal(s) if s == section))
+    }
 
-        // Make sure we unlock all perks/badges/automations
-        // which have their conditions met.
-        macro_rules! unlock_perk {
-            ($name:ident) => {{
-                let mut unlocks = Vec::new();
-                for (id, inst) in self.$name.iter().enumerate() {
-                    if self.check_conditions(&inst.condition()) {
-                        unlocks.push(id);
-                    }
+    // Completes the stages whose objectives are met, in order.
+    fn advance_stages(&mut self) {
+        while self.stage < self.stages.len()
+            && self.check_conditions(&self.stages[self.stage].objective.0)
+        {
+            let effects = self.stages[self.stage].effects.clone();
+            for effect in effects {
+                match effect {
+                    StageEffect::UnlockProduct(id) => self.activate_product(id),
+                    StageEffect::UnlockMaterial(id) => self.materials[id].activate(),
+                    StageEffect::Demand(id, demand) => self.products[id].demand = demand,
+                    StageEffect::SetMoney(money) => self.money = money,
+                    StageEffect::SetMaterial(id, cnt) => self.materials[id].count = cnt,
+                    StageEffect::SetProduct(id, cnt) => self.products[id].count = cnt,
+                    StageEffect::Reveal(_) => {}
                 }
+            }
 
-                for id in unlocks.iter() {
-                    self.$name[*id].unlock();
+            self.events.push(Event::StageCompleted(self.stage));
+            self.stage += 1;
+        }
+    }
+
+    // Records the win and moves on as defined by the post-win behaviour.
+    fn win_game(&mut self) {
+        self.wins.push(WinRecord {
+            time: self.time,
+            money: self.money,
+            produced: self.products.iter().map(|p| p.produced()).collect(),
+            sold: self.products.iter().map(|p| p.sold()).collect(),
+            bought: self.materials.iter().map(|m| m.bought()).collect(),
+        });
+        self.events.push(Event::Won);
+
+        match std::mem::take(&mut self.post_win) {
+            PostWin::NewObjective(objective) => self.objective = objective,
+            post_win => {
+                self.post_win = post_win;
+                self.outcome = Outcome::Won;
+            }
+        }
+    }
+
+    // Reset layer of the game, if any.
+    pub fn prestige_layer(&self) -> Option<&Prestige> {
+        self.prestige.as_ref()
+    }
+
+    // Amount of the prestige currency a prestige reset would award right now.
+    // None if the game has no prestige.
+    pub fn prestige_reward(&self) -> Option<Price> {
+        let prestige = self.prestige.as_ref()?;
+
+        let sold: Count = self.products.iter().map(|p| p.sold()).sum();
+        let produced: Count = self.products.iter().map(|p| p.produced()).sum();
+        let time = self.time.as_secs_f64();
+        let vars = [
+            ("money", self.money),
+            ("sold", sold as f64),
+            ("produced", produced as f64),
+            ("time", time),
+            ("prestiges", self.lifetime.prestiges as f64),
+            ("total_time", self.lifetime.time.as_secs_f64() + time),
+            ("total_produced", (self.lifetime.produced + produced) as f64),
+            ("total_sold", (self.lifetime.sold + sold) as f64),
+        ];
+
+        Some(eval_expr(&prestige.reward, &vars).floor().max(0.0))
+    }
+
+    // Whether the prestige conditions are met and it would award anything.
+    // Never true before the conditions are compiled.
+    pub fn can_prestige(&self) -> bool {
+        match (&self.prestige, self.prestige_reward()) {
+            (Some(prestige), Some(reward)) => {
+                self.initial.is_some() && reward > 0.0 && self.check_conditions(&prestige.condition)
+            }
+            _ => false,
+        }
+    }
+
+    // Awards the prestige currency and resets the progress as defined by the [`Prestige`].
+    // Progress is reset to what it was when the reset point was recorded,
+    // see [`State::record_reset_point`].
+    // Returns whether the prestige was done.
+    pub fn prestige(&mut self) -> bool {
+        if !self.can_prestige() {
+            return false;
+        }
+        let reward = self.prestige_reward().unwrap();
+
+        self.lifetime.prestiges += 1;
+        self.lifetime.sold += self.products.iter().map(|p| p.sold()).sum::<Count>();
+        self.lifetime.produced += self.products.iter().map(|p| p.produced()).sum::<Count>();
+        self.lifetime.time += self.time;
+
+        let prestige = self.prestige.as_ref().unwrap();
+        let (currency, reset) = (prestige.currency, prestige.reset.clone());
+        self.reset_progress(&reset);
+        self.currencies[currency].amount += reward;
+        self.events.push(Event::Prestige(reward));
+
+        true
+    }
+
+    // Restores the given parts of the progress to the initial ones and starts a new run.
+    // The prestige currency, the persistent perks and the rewards of
+    // completed challenges are kept, as are the rules of the current challenge.
+    fn reset_progress(&mut self, scopes: &[ResetScope]) {
+        let initial = self.initial.as_ref().unwrap();
+        let kept_currency = self.prestige.as_ref().map(|p| p.currency);
+
+        for scope in scopes.iter() {
+            match scope {
+                ResetScope::Money => self.money = initial.money,
+                ResetScope::Currencies => {
+                    for (id, currency) in initial.currencies.iter().enumerate() {
+                        if Some(id) != kept_currency {
+                            self.currencies[id] = currency.clone();
+                        }
+                    }
+                }
+                ResetScope::Materials => self.materials = initial.materials.clone(),
+                ResetScope::Products => {
+                    self.products = initial.products.clone();
+                    self.queue.clear();
+                }
+                ResetScope::Perks => {
+                    for (id, perk) in initial.perks.iter().enumerate() {
+                        if !self.perks[id].persistent {
+                            self.perks[id] = perk.clone();
+                        }
+                    }
+                }
+                ResetScope::Automations => self.automations = initial.automations.clone(),
+                ResetScope::Badges => self.badges = initial.badges.clone(),
+                ResetScope::Stages => self.stage = 0,
+            }
+        }
+
+        for id in 0..self.challenges.len() {
+            if !self.challenges[id].completed {
+                continue;
+            }
+            for perk in self.challenges[id].reward.clone() {
+                self.perks[perk].unlock();
+                self.perks[perk].activate();
+            }
+        }
+
+        if let Some(id) = self.challenge {
+            for rule in self.challenges[id].rules.iter() {
+                match *rule {
+                    ChallengeRule::NoAutomations => {}
+                    ChallengeRule::MaterialGrowth(scale) => {
+                        for m in self.materials.iter_mut() {
+                            m.growth_scale = scale;
+                        }
+                    }
+                    // Applied where the demand is read, see [`State::demand`].
+                    ChallengeRule::Demand(_) => {}
+                }
+            }
+        }
+
+        // A new objective set after a win only lasts until the next reset.
+        self.objective = initial.objective.clone();
+        self.post_win = initial.post_win.clone();
+
+        self.time = Duration::ZERO;
+        self.outcome = Outcome::InProgress;
+        self.events.clear();
+    }
+
+    // Whether a challenge can be started right now.
+    // Never true before the reset point is recorded.
+    pub fn can_start_challenge(&self, id: ChallengeId) -> bool {
+        self.initial.is_some() && self.challenge.is_none() && !self.challenges[id].completed
+    }
+
+    // Starts a run of a challenge. The progress is reset
+    // and the challenge's rules applied to the new run.
+    pub fn start_challenge(&mut self, id: ChallengeId) {
+        assert!(self.can_start_challenge(id));
+
+        self.challenge = Some(id);
+        self.reset_progress(&default_reset());
+    }
+
+    // Gives up the current challenge, if any, resetting the progress.
+    pub fn abandon_challenge(&mut self) {
+        if self.challenge.take().is_some() {
+            self.reset_progress(&default_reset());
+        }
+    }
+
+    // Completes the current challenge once its goal is met.
+    fn check_challenge(&mut self) {
+        let id = match self.challenge {
+            Some(id) if self.check_conditions(&self.challenges[id].goal.0) => id,
+            _ => return,
+        };
+
+        self.challenges[id].completed = true;
+        self.challenge = None;
+        self.reset_progress(&default_reset());
+        self.events.push(Event::ChallengeCompleted(id));
+    }
+
+    // Whether a rule of the current challenge applies.
+    fn challenge_rule(&self, rule: ChallengeRule) -> bool {
+        self.challenge
+            .is_some_and(|id| self.challenges[id].rules.contains(&rule))
+    }
+
+    // Demand of a product with the rules of the current challenge applied.
+    // See [`Product::interest`]
+    pub fn demand(&self, id: ProductId) -> f64 {
+        self.products[id].demand() * self.challenge_demand()
+    }
+
+    // Multiplier of the demand of every product in the current challenge, 1 if none.
+    fn challenge_demand(&self) -> f64 {
+        let Some(id) = self.challenge else {
+            return 1.0;
+        };
+
+        self.challenges[id]
+            .rules
+            .iter()
+            .map(|rule| match *rule {
+                ChallengeRule::Demand(demand) => demand,
+                _ => 1.0,
+            })
+            .product()
+    }
+
+    // Whether the game is over and is no longer updated.
+    pub fn finished(&self) -> bool {
+        match self.outcome {
+            Outcome::InProgress => false,
+            Outcome::Won => matches!(self.post_win, PostWin::Stop),
+            Outcome::Lost(_) => true,
+        }
+    }
+
+    // Money paid per second for the upkeep of the materials and products in stock.
+    pub fn upkeep(&self) -> Price {
+        let materials = self
+            .materials
+            .iter()
+            .filter(|m| m.active)
+            .map(|m| m.count as f64 * m.upkeep);
+        let products = self
+            .products
+            .iter()
+            .filter(|p| p.active)
+            .map(|p| p.count as f64 * p.upkeep);
+
+        materials.chain(products).sum()
+    }
+
+    // Whether the money went below zero paying the upkeep.
+    pub fn bankrupt(&self) -> bool {
+        self.money < 0.0
+    }
+
+    // Pays the upkeep of the stock for `delta` and handles running out of money.
+    fn pay_upkeep(&mut self, delta: Duration) {
+        let upkeep = self.upkeep() * delta.as_secs_f64();
+        if upkeep <= 0.0 {
+            return;
+        }
+
+        // The policy applies on every tick while bankrupt, the event only when going bankrupt.
+        let was_bankrupt = self.bankrupt();
+        self.money -= upkeep;
+        if !self.bankrupt() {
+            return;
+        }
+
+        if !was_bankrupt {
+            self.events.push(Event::Bankrupt);
+        }
+        match self.bankruptcy {
+            Bankruptcy::PauseAutomations => {}
+            Bankruptcy::SellMaterials => {
+                for id in (0..self.materials.len()).rev() {
+                    if self.materials[id].currency.is_some() {
+                        continue;
+                    }
+
+                    // Units refunded nothing don't help paying off the debt.
+                    let mut sold = 0;
+                    while self.bankrupt() && self.material_refund(id) > 0.0 {
+                        self.sell_material(id, 1);
+                        sold += 1;
+                    }
+                    if sold > 0 {
+                        self.events.push(Event::MaterialSoldOff(id, sold));
+                    }
+                }
+            }
+            Bankruptcy::Lose => self.outcome = Outcome::Lost(LoseReason::Bankruptcy),
+        }
+    }
+
+    // Iterates over the queued orders of a product.
+    pub fn orders(&self, id: ProductId) -> impl Iterator<Item = &Order> {
+        self.queue.iter().filter(move |order| order.product == id)
+    }
+
+    // Works on the queued orders with free slots and completes the finished ones.
+    //
+    // Every slot is worked for the whole delta: when its order finishes, the
+    // time left over goes to the next waiting order of the same product, so
+    // one long update builds as much as several short ones.
+    fn advance_queue(&mut self, delta: Duration) {
+        let slots: Vec<_> = (0..self.products.len()).map(|id| self.slots(id)).collect();
+        let mut lanes: Vec<Vec<Duration>> = vec![Vec::new(); self.products.len()];
+
+        let mut finished = Vec::new();
+        for (i, order) in self.queue.iter_mut().enumerate() {
+            let lanes = &mut lanes[order.product];
+            let lane = if (lanes.len() as Count) < slots[order.product].unwrap_or(Count::MAX) {
+                lanes.push(delta);
+                lanes.last_mut()
+            } else {
+                // A slot whose order finished within this delta and still has time left.
+                lanes
+                    .iter_mut()
+                    .filter(|left| !left.is_zero())
+                    .max_by_key(|left| **left)
+            };
+            let Some(left) = lane else {
+                continue;
+            };
+
+            let needed = order.remaining();
+            if *left >= needed {
+                *left -= needed;
+                order.elapsed = order.duration;
+                finished.push(i);
+            } else {
+                order.elapsed += *left;
+                *left = Duration::ZERO;
+            }
+        }
+
+        for i in finished.into_iter().rev() {
+            let order = self.queue.remove(i);
+            self.complete_builds(order.product, 1);
+        }
+    }
+
+    // Adds a byproduct of a construction. Materials never exceed their limit.
+    // Returns the id of the product whose count changed, if any.
+    fn add_output(&mut self, output: Quantity) -> Option<ProductId> {
+        match output {
+            Quantity::Money(money) => self.money += money,
+            Quantity::Currency(id, amount) => self.currencies[id].amount += amount,
+            Quantity::Material(id, cnt) => {
+                let limit = self.material_limit(id).unwrap_or(Count::MAX);
+                let m = &mut self.materials[id];
+                m.count = m.count.saturating_add(cnt).min(limit.max(m.count));
+            }
+            Quantity::Product(id, cnt, _) => {
+                self.products[id].build(cnt);
+                return Some(id);
+            }
+        }
+
+        None
+    }
+
+    fn unlock_products(&mut self, id: ProductId) {
+        let mut products_to_activate = Vec::new();
+        for (unlock_id, cnt) in self.products[id].unlocks() {
+            if self.products[id].count > *cnt {
+                products_to_activate.push(*unlock_id);
+            }
+        }
+
+        for id in products_to_activate {
+            self.activate_product(id);
+        }
+    }
+
+    fn sell_product(&mut self, id: ProductId) {
+        let product = &self.products[id];
+        let price = match self.sell_price(id) {
+            Some(price) => price,
+            None => return,
+        };
+        let interest = self.interest(id);
+
+        if interest == 0.0 || product.count() == 0 {
+            return;
+        }
+
+        let interest = interest.min(1.);
+        let mut rng = rand::thread_rng();
+        let sold = if rng.gen_bool(interest / 100.0) { 1 } else { 0 };
+        if sold == 0 {
+            return;
+        }
+
+        let mut sell_multiplier: Option<Count> = None;
+        for dep in product.dependencies().iter() {
+            if dep.kind() != RelationKind::SoldBy {
+                continue;
+            }
+            let cnt = self.quantity_present_count(dep.quantity());
+            if cnt == 0 {
+                continue;
+            }
+
+            sell_multiplier = match sell_multiplier {
+                Some(mult) => Some(mult.min(cnt)),
+                None => Some(cnt),
+            };
+        }
+        let sold = (sold * sell_multiplier.unwrap_or(1)).min(product.count());
+
+        let product = &mut self.products[id];
+        product.sell(sold);
+
+        let earned = price * sold as f64;
+        let currency = product.currency();
+        *self.balance_mut(currency) += earned;
+    }
+
+    // Constructs as many of the product as its dependencies allow.
+    // Products with a build time are instead queued a single construction
+    // at a time and are completed by [`State::update`].
+    pub fn construct_product(&mut self, id: ProductId) {
+        let report = self.build_report(id);
+
+        if !report.can_build() {
+            return;
+        }
+
+        match self.products[id].build_time() {
+            Some(duration) => {
+                self.consume_dependencies(&report, 1);
+                self.queue.push(Order {
+                    product: id,
+                    duration,
+                    elapsed: Duration::ZERO,
+                });
+            }
+            None => {
+                self.consume_dependencies(&report, report.max_builds());
+                self.complete_builds(id, report.max_builds());
+            }
+        }
+    }
+
+    pub fn update(&mut self, delta: Duration) {
+        if self.finished() {
+            return;
+        }
+
+        self.time += delta;
+        self.events.clear();
+
+        self.regenerate(delta);
+        self.decay(delta);
+
+        // Sell available goods
+        for id in 0..self.products.len() {
+            if !self.products[id].active() {
+                continue;
+            }
+
+            self.sell_product(id);
+        }
+
+        self.pay_upkeep(delta);
+        if self.lost() {
+            return;
+        }
+
+        // Automated products construction
+        let mut products_to_builds = Vec::new();
+        let mut materials_to_buy = Vec::new();
+        let halted = self.bankrupt() || self.challenge_rule(ChallengeRule::NoAutomations);
+        let timers: Vec<_> = (0..self.automations.len())
+            .map(|id| self.automation_timer(id))
+            .collect();
+        let batches: Vec<_> = (0..self.automations.len())
+            .map(|id| self.automation_batch(id))
+            .collect();
+        for (i, automation) in self.automations.iter_mut().enumerate() {
+            if !automation.active() || automation.paused() || halted {
+                continue;
+            }
+            let run = match (automation.timer(), timers[i]) {
+                (Some(timer), Some(duration)) => timer.tick_over(delta, duration),
+                _ => true,
+            };
+
+            if !run || self.money < automation.upkeep {
+                continue;
+            }
+            self.money -= automation.upkeep;
+
+            match automation.kind() {
+                AutomationKind::Buy(id) => materials_to_buy.push((id, batches[i])),
+                AutomationKind::Build(id) => products_to_builds.push((id, batches[i])),
+            }
+        }
+        for (id, batch) in products_to_builds {
+            for _ in 0..batch {
+                self.construct_product(id);
+            }
+        }
+
+        for (id, batch) in materials_to_buy {
+            self.buy_material(id, u32::try_from(batch).unwrap_or(u32::MAX));
+        }
+
+        self.advance_queue(delta);
+
+        let lost = self
+            .lose_conditions
+            .iter()
+            .position(|lose| !lose.condition.is_empty() && self.check_conditions(&lose.condition));
+        if let Some(id) = lost {
+            self.outcome = Outcome::Lost(LoseReason::Condition(id));
+            return;
+        }
+
+        self.advance_stages();
+
+        // A game without stages or objective is never won.
+        // Neither can it be won during a challenge.
+        let has_objective = !self.stages.is_empty() || !self.objective.0.is_empty();
+        if self.challenge.is_some() {
+            self.check_challenge();
+        } else if has_objective
+            && !self.win()
+            && self.stage == self.stages.len()
+            && self.check_conditions(&self.objective.0)
+        {
+            self.win_game();
+        }
+
+        if self.finished() {
+            return;
+        }
+
+        // Make sure we unlock all perks/badges/automations
+        // which have their conditions met.
+        macro_rules! unlock_perk {
+            ($name:ident) => {{
+                let mut unlocks = Vec::new();
+                for (id, inst) in self.$name.iter().enumerate() {
+                    if self.check_conditions(&inst.condition()) {
+                        unlocks.push(id);
+                    }
+                }
+
+                for id in unlocks.iter() {
+                    self.$name[*id].unlock();
                 }
 
                 unlocks
@@ -1077,8 +4380,20 @@ impl State {
 
         unlock_perk!(badges);
 
-        let unlocked = unlock_perk!(perks);
-        unlock_or_activate!(perks, unlocked);
+        // Perks also need their parents to be active and to not be locked.
+        // They are checked one at a time, so exclusive perks without a price
+        // don't activate together.
+        self.relock_perks();
+        for id in 0..self.perks.len() {
+            if !self.perk_available(id) || !self.check_conditions(&self.perks[id].condition) {
+                continue;
+            }
+
+            if self.perks[id].price().is_empty() {
+                self.perks[id].activate();
+            }
+            self.perks[id].unlock();
+        }
 
         let unlocked = unlock_perk!(automations);
         unlock_or_activate!(automations, unlocked);
@@ -1086,42 +4401,65 @@ impl State {
 
     pub fn buy_material(&mut self, id: ProductMaterialId, cnt: u32) {
         for _ in 0..cnt {
-            let m = &mut self.materials[id];
-            if m.count() >= m.limit().unwrap_or(Count::MAX) {
+            let m = &self.materials[id];
+            if m.count() >= self.material_limit(id).unwrap_or(Count::MAX) {
                 continue;
             }
 
-            let price = m.price();
-            if self.money >= price {
-                m.buy();
-                self.money -= price;
+            let price = self.material_price(id);
+            let currency = m.currency();
+            if self.balance(currency) >= price {
+                self.materials[id].buy(price);
+                *self.balance_mut(currency) -= price;
+            }
+        }
+    }
+
+    // Sells up to `cnt` units of a material back, refunding
+    // the currency it is bought with. See [`ProductMaterial::refund`]
+    pub fn sell_material(&mut self, id: ProductMaterialId, cnt: u32) {
+        for _ in 0..cnt {
+            let m = &self.materials[id];
+            if m.count() == 0 {
+                break;
             }
+
+            let refund = self.material_refund(id);
+            let currency = m.currency();
+            self.materials[id].sell();
+            *self.balance_mut(currency) += refund;
         }
     }
 
-    pub fn buy_perk(&mut self, id: PerkId) {
+    // Buys the next level of a perk if the player can afford it.
+    // Returns the level of the perk after the purchase.
+    pub fn buy_perk(&mut self, id: PerkId) -> Count {
         assert!(self.perks[id].unlocked);
-        assert!(!self.perks[id].active);
+        assert!(!self.perks[id].maxed());
+        assert!(!self.perk_locked(id));
 
-        let v: Vec<_> = self.perks[id]
-            .price()
+        let price = self.perks[id].next_price();
+        let v: Vec<_> = price
             .iter()
             .map(|p| match *p {
                 Quantity::Product(id, cnt, _) => {
-                    Quantity::Product(id, cnt, Some(ProductConditionKind::Count))
+                    Condition::Product(id, cnt, Some(ProductConditionKind::Count))
                 }
-                _ => *p,
+                _ => Condition::from(*p),
             })
             .collect();
         if !self.check_conditions(&v) {
-            return;
+            return self.perks[id].level;
         }
 
-        for price in self.perks[id].price() {
+        for price in &price {
             match price {
                 Quantity::Money(money) => {
                     self.money -= money;
                 }
+                Quantity::Currency(id, amount) => {
+                    self.currencies[*id].amount -= amount;
+                }
                 Quantity::Material(id, cnt) => {
                     self.materials[*id].count -= cnt;
                 }
@@ -1131,22 +4469,108 @@ impl State {
             }
         }
 
-        self.perks[id].activate();
+        self.perks[id].level_up();
+        self.relock_perks();
+        self.perks[id].level
     }
 
-    pub fn buy_automation(&mut self, id: AutomationId) {
-        assert!(self.automations[id].unlocked);
-        assert!(!self.automations[id].active);
+    // Whether an active perk is exclusive with the perk, so it can never be bought.
+    // Exclusivity goes both ways, no matter which of the perks declares it.
+    pub fn perk_locked(&self, id: PerkId) -> bool {
+        let perk = &self.perks[id];
+        if perk.active() {
+            return false;
+        }
 
-        let v: Vec<_> = self.automations[id]
-            .price()
+        perk.excludes
             .iter()
-            .map(|p| match *p {
-                Quantity::Product(id, cnt, _) => {
-                    Quantity::Product(id, cnt, Some(ProductConditionKind::Count))
-                }
-                _ => *p,
-            })
+            .any(|other| self.perks[*other].active())
+            || self
+                .perks
+                .iter()
+                .any(|other| other.active() && other.excludes.contains(&id))
+    }
+
+    // Whether the perk may be unlocked as far as the perk tree is concerned,
+    // i.e all its parents are active and it's not locked.
+    pub fn perk_available(&self, id: PerkId) -> bool {
+        self.perks[id]
+            .requires
+            .iter()
+            .all(|parent| self.perks[*parent].active())
+            && !self.perk_locked(id)
+    }
+
+    // Hides the perks that became unavailable, f.e when an exclusive perk was bought.
+    fn relock_perks(&mut self) {
+        for id in 0..self.perks.len() {
+            if !self.perks[id].active() && !self.perk_available(id) {
+                self.perks[id].unlocked = false;
+            }
+        }
+    }
+
+    // The perks as a tree, in the order of their ids.
+    // Perks with no parents are the roots of the tree.
+    pub fn perk_tree(&self) -> Vec<PerkNode> {
+        let n = self.perks.len();
+
+        // Longest chain of parents, relaxed at most `n` times so cycles,
+        // reported by [`State::analyze`], don't loop forever.
+        let mut depth = vec![0; n];
+        for _ in 0..n {
+            let mut changed = false;
+            for (id, perk) in self.perks.iter().enumerate() {
+                for parent in perk.requires.iter() {
+                    let through = (depth[*parent] + 1).min(n);
+                    if through > depth[id] {
+                        depth[id] = through;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        (0..n)
+            .map(|id| {
+                let children = (0..n)
+                    .filter(|child| self.perks[*child].requires.contains(&id))
+                    .collect();
+                let exclusive = (0..n)
+                    .filter(|other| {
+                        self.perks[id].excludes.contains(other)
+                            || self.perks[*other].excludes.contains(&id)
+                    })
+                    .collect();
+
+                PerkNode {
+                    perk: id,
+                    parents: self.perks[id].requires.clone(),
+                    children,
+                    exclusive,
+                    depth: depth[id],
+                    locked: self.perk_locked(id),
+                }
+            })
+            .collect()
+    }
+
+    pub fn buy_automation(&mut self, id: AutomationId) {
+        assert!(self.automations[id].unlocked);
+        assert!(!self.automations[id].active);
+
+        let v: Vec<_> = self.automations[id]
+            .price()
+            .iter()
+            .map(|p| match *p {
+                Quantity::Product(id, cnt, _) => {
+                    Condition::Product(id, cnt, Some(ProductConditionKind::Count))
+                }
+                _ => Condition::from(*p),
+            })
             .collect();
         if !self.check_conditions(&v) {
             return;
@@ -1157,6 +4581,9 @@ impl State {
                 Quantity::Money(money) => {
                     self.money -= money;
                 }
+                Quantity::Currency(id, amount) => {
+                    self.currencies[*id].amount -= amount;
+                }
                 Quantity::Material(id, cnt) => {
                     self.materials[*id].count -= cnt;
                 }
@@ -1193,13 +4620,32 @@ impl State {
     }
 
     pub fn win(&self) -> bool {
-        self.win
+        self.outcome == Outcome::Won
+    }
+
+    pub fn lost(&self) -> bool {
+        matches!(self.outcome, Outcome::Lost(_))
+    }
+
+    pub fn time_played(&self) -> Duration {
+        self.time
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Product, State};
+    use std::time::Duration;
+
+    use either::Either;
+
+    use crate::timer::Timer;
+
+    use super::{
+        Automation, AutomationKind, Bankruptcy, Challenge, ChallengeRule, Comparison, Condition,
+        Currency, Decay, Event, Lifetime, LoseCondition, LoseReason, Objective, Outcome, Perk,
+        PerkKind, PerkScope, PerkTarget, PerkValue, PostWin, Prestige, Product, ProductMaterial,
+        Quantity, RandomYield, Relation, RelationKind, ResetScope, Stage, StageEffect, Stat, State,
+    };
 
     #[test]
     fn dec_price() {
@@ -1219,4 +4665,1198 @@ mod tests {
         s.dec_price(0, -1.);
         assert!(s.products[0].price().unwrap() >= 0.0);
     }
+
+    #[test]
+    fn effective_recipe() {
+        let mut s = State {
+            materials: vec![ProductMaterial::new(
+                0,
+                None,
+                "lemon".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )],
+            products: vec![Product::new(
+                "test".to_string(),
+                Some(1.0),
+                vec![
+                    Relation::new(RelationKind::Consumes, Quantity::Material(0, 10)),
+                    Relation::new(RelationKind::SoldBy, Quantity::Money(1.0)),
+                ],
+                vec![0, 1],
+                vec![],
+                true,
+            )],
+            perks: vec![
+                Perk::new(
+                    "half".to_string(),
+                    "".to_string(),
+                    vec![],
+                    vec![],
+                    (Quantity::Material(0, 2), PerkKind::Divide),
+                ),
+                Perk::new(
+                    "more".to_string(),
+                    "".to_string(),
+                    vec![],
+                    vec![],
+                    (Quantity::Product(0, 10, None), PerkKind::Multiply),
+                ),
+            ],
+            ..Default::default()
+        };
+
+        let recipe = s.effective_recipe(0);
+        assert_eq!(recipe.entries().len(), 2);
+        assert!(!recipe.entries()[0].modified());
+        assert_eq!(recipe.effective_yield(), 1);
+
+        s.perks[0].activate();
+        s.perks[1].activate();
+
+        let recipe = s.effective_recipe(0);
+        assert_eq!(recipe.entries()[0].effective().quantity(), 5.0);
+        assert!(!recipe.entries()[1].modified());
+        assert_eq!(recipe.entries_of(RelationKind::SoldBy).count(), 1);
+        assert_eq!(recipe.base_yield(), 1);
+        assert_eq!(recipe.effective_yield(), 10);
+    }
+
+    #[test]
+    fn build_report() {
+        let mut s = State {
+            money: 10.0,
+            materials: vec![
+                ProductMaterial::new(1, None, "shop".to_string(), 1.0, Either::Left(1.0), true),
+                ProductMaterial::new(5, None, "lemon".to_string(), 1.0, Either::Left(1.0), true),
+            ],
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![
+                    Relation::new(RelationKind::ManufacturedBy, Quantity::Material(0, 1)),
+                    Relation::new(RelationKind::Consumes, Quantity::Material(1, 2)),
+                    Relation::new(RelationKind::Consumes, Quantity::Money(1.0)),
+                ],
+                vec![],
+                vec![],
+                true,
+            )],
+            ..Default::default()
+        };
+
+        let report = s.build_report(0);
+        assert_eq!(report.max_builds(), 1);
+        assert_eq!(report.limiting(), Some(0));
+
+        s.construct_product(0);
+        assert_eq!(s.products[0].count(), 1);
+        assert_eq!(s.materials[1].count(), 3);
+        assert_eq!(s.money(), 9.0);
+
+        s.materials[1].count = 1;
+        let report = s.build_report(0);
+        assert!(!report.can_build());
+        assert_eq!(report.limiting(), Some(1));
+        assert_eq!(report.limiting_dependency().unwrap().available(), 1.0);
+    }
+
+    #[test]
+    fn currencies() {
+        let mut s = State {
+            money: 10.0,
+            currencies: vec![Currency::new("reputation".to_string(), 3.0)],
+            materials: vec![ProductMaterial::new(
+                0,
+                None,
+                "lemon".to_string(),
+                2.0,
+                Either::Left(1.0),
+                true,
+            )
+            .with_currency(0)],
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![
+                    Relation::new(RelationKind::ManufacturedBy, Quantity::Material(0, 1)),
+                    Relation::consumes(Quantity::Currency(0, 0.5)),
+                ],
+                vec![],
+                vec![],
+                true,
+            )
+            .with_currency(0)],
+            ..Default::default()
+        };
+
+        s.buy_material(0, 2);
+        assert_eq!(s.materials[0].count(), 1);
+        assert_eq!(s.balance(Some(0)), 1.0);
+        assert_eq!(s.money(), 10.0);
+
+        s.construct_product(0);
+        assert_eq!(s.products[0].count(), 1);
+        assert_eq!(s.balance(Some(0)), 0.5);
+        assert!(s.check_condition(&Condition::Currency(0, 0.5)));
+        assert!(!s.check_condition(&Quantity::Currency(0, 1.0).into()));
+        s.products[0].outputs = vec![Quantity::Currency(1, 1.0)];
+        assert!(s.compile_conditions().is_err());
+    }
+
+    #[test]
+    fn byproducts() {
+        let mut s = State {
+            materials: vec![
+                ProductMaterial::new(4, None, "lemon".to_string(), 1.0, Either::Left(1.0), true),
+                ProductMaterial::new(0, Some(5), "peel".to_string(), 1.0, Either::Left(1.0), true),
+            ],
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![Relation::consumes(Quantity::Material(0, 2))],
+                vec![0],
+                vec![],
+                true,
+            )
+            .with_outputs(vec![Quantity::Material(1, 1), Quantity::Money(0.5)])],
+            perks: vec![Perk::new(
+                "zest".to_string(),
+                "".to_string(),
+                vec![],
+                vec![],
+                (Quantity::Material(1, 2), PerkKind::Multiply),
+            )],
+            ..Default::default()
+        };
+
+        let report = s.build_report(0);
+        assert_eq!(report.outputs()[0].quantity(), 2.0);
+
+        s.perks[0].activate();
+        assert_eq!(
+            s.effective_recipe(0).outputs()[0].effective().quantity(),
+            2.0
+        );
+
+        s.construct_product(0);
+        assert_eq!(s.products[0].count(), 2);
+        assert_eq!(s.materials[1].count(), 4);
+        assert_eq!(s.money(), 1.0);
+
+        s.materials[0].count = 4;
+        s.construct_product(0);
+        assert_eq!(s.materials[1].count(), 5);
+    }
+
+    #[test]
+    fn random_yield() {
+        let mut s = State {
+            materials: vec![ProductMaterial::new(
+                0,
+                None,
+                "seed".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )],
+            money: 1.0,
+            products: vec![Product::new(
+                "lemon".to_string(),
+                None,
+                vec![Relation::consumes(Quantity::Money(1.0))],
+                vec![0],
+                vec![],
+                true,
+            )
+            .with_random(RandomYield::new(
+                (1, 3),
+                vec![(Quantity::Material(0, 1), 1.0)],
+                0.5,
+                2.0,
+            ))],
+            perks: vec![serde_yaml::from_str(
+                r#"
+name: "Lucky"
+desc: ""
+condition: []
+buy_price: []
+perk: [!CritChance 0.5, Add]
+"#,
+            )
+            .unwrap()],
+            ..Default::default()
+        };
+
+        let recipe = s.effective_recipe(0);
+        assert_eq!(recipe.effective_yield(), 1);
+        assert_eq!(recipe.expected_yield(), 3.0);
+        assert_eq!(recipe.outputs()[0].expected(), 1.0);
+
+        s.perks[0].activate();
+        assert_eq!(s.crit_chance(0), 1.0);
+
+        s.products[0].random.units = (2, 2);
+        s.construct_product(0);
+        assert_eq!(s.products[0].count(), 4);
+        assert_eq!(s.materials[0].count(), 1);
+
+        s.products[0].random.units = (1, 1_000_000_000);
+        assert!((s.expected_yield(0) / 1e9 - 1.0).abs() < 1e-3);
+        s.products[0].random.units = (1, 1999);
+        assert_eq!(s.expected_yield(0), 2000.0);
+    }
+
+    #[test]
+    fn production_queue() {
+        let mut s = State {
+            money: 3.0,
+            materials: vec![ProductMaterial::new(
+                1,
+                None,
+                "press".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )],
+            products: vec![Product::new(
+                "juice".to_string(),
+                None,
+                vec![
+                    Relation::new(RelationKind::ManufacturedBy, Quantity::Material(0, 1))
+                        .with_capacity(2),
+                    Relation::consumes(Quantity::Money(1.0)),
+                ],
+                vec![],
+                vec![],
+                true,
+            )
+            .with_build_time(Duration::from_secs(1))],
+            ..Default::default()
+        };
+
+        for _ in 0..3 {
+            s.construct_product(0);
+        }
+        assert_eq!(s.queue().len(), 3);
+        assert_eq!(s.money(), 0.0);
+        assert_eq!(s.slots(0), Some(2));
+
+        s.update(Duration::from_millis(500));
+        assert_eq!(s.queue()[0].progress(), 0.5);
+        assert!(!s.queue()[2].started());
+
+        s.update(Duration::from_millis(500));
+        assert_eq!(s.products[0].count(), 2);
+        assert_eq!(s.orders(0).count(), 1);
+
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.products[0].count(), 3);
+        assert!(s.queue().is_empty());
+    }
+
+    #[test]
+    fn production_queue_delta() {
+        let build = || {
+            let mut s = State {
+                money: 10.0,
+                materials: vec![ProductMaterial::new(
+                    1,
+                    None,
+                    "press".to_string(),
+                    1.0,
+                    Either::Left(1.0),
+                    true,
+                )],
+                products: vec![Product::new(
+                    "juice".to_string(),
+                    None,
+                    vec![
+                        Relation::new(RelationKind::ManufacturedBy, Quantity::Material(0, 1)),
+                        Relation::consumes(Quantity::Money(1.0)),
+                    ],
+                    vec![],
+                    vec![],
+                    true,
+                )
+                .with_build_time(Duration::from_secs(1))],
+                ..Default::default()
+            };
+
+            for _ in 0..10 {
+                s.construct_product(0);
+            }
+            s
+        };
+
+        let mut once = build();
+        once.update(Duration::from_millis(9500));
+
+        let mut steps = build();
+        for _ in 0..19 {
+            steps.update(Duration::from_millis(500));
+        }
+
+        assert_eq!(once.products[0].count(), 9);
+        assert_eq!(steps.products[0].count(), 9);
+        assert_eq!(once.queue()[0].progress(), 0.5);
+        assert_eq!(steps.queue()[0].progress(), 0.5);
+    }
+
+    #[test]
+    fn regeneration() {
+        let mut s = State {
+            materials: vec![ProductMaterial::new(
+                0,
+                Some(10),
+                "stamina".to_string(),
+                0.0,
+                Either::Left(1.0),
+                true,
+            )
+            .with_regeneration(2.5)],
+            perks: vec![Perk::new(
+                "second wind".to_string(),
+                "".to_string(),
+                vec![],
+                vec![Quantity::Money(1.0)],
+                (PerkTarget::Regeneration(0, 2.0), PerkKind::Multiply),
+            )],
+            ..Default::default()
+        };
+
+        s.update(Duration::from_millis(500));
+        s.update(Duration::from_millis(500));
+        assert_eq!(s.materials[0].count(), 2);
+
+        s.perks[0].activate();
+        assert_eq!(s.regeneration(0), 5.0);
+
+        s.update(Duration::from_secs(3600));
+        assert_eq!(s.materials[0].count(), 10);
+    }
+
+    #[test]
+    fn decay() {
+        let mut s = State {
+            materials: vec![ProductMaterial::new(
+                8,
+                None,
+                "lemon".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )
+            .with_decay(Decay::Fraction(0.5))],
+            products: vec![
+                Product::new("juice".to_string(), None, vec![], vec![], vec![], true)
+                    .with_decay(Decay::ShelfLife(2.0)),
+            ],
+            perks: vec![Perk::new(
+                "fridge".to_string(),
+                "".to_string(),
+                vec![],
+                vec![Quantity::Money(1.0)],
+                (PerkTarget::MaterialDecay(0, 0.5), PerkKind::Multiply),
+            )],
+            ..Default::default()
+        };
+        s.products[0].count = 3;
+
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.materials[0].count(), 4);
+        assert_eq!(s.events(), &[Event::MaterialDecayed(0, 4)]);
+
+        // the first batch goes bad, the new one is still fresh
+        s.products[0].count = 5;
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.materials[0].count(), 2);
+        assert_eq!(s.products[0].count(), 2);
+        assert_eq!(s.stat(Stat::ProductDecayed(0)), 3.0);
+        assert_eq!(
+            s.events(),
+            &[Event::MaterialDecayed(0, 2), Event::ProductDecayed(0, 3)]
+        );
+
+        s.perks[0].activate();
+        assert_eq!(s.material_decay(0), Some(Decay::Fraction(0.25)));
+
+        // halving the spoilage doubles the shelf life
+        s.perks[0].perk.0 = PerkTarget::ProductDecay(0, 0.5);
+        assert_eq!(s.product_decay(0), Some(Decay::ShelfLife(4.0)));
+    }
+
+    #[test]
+    fn sell_material() {
+        let mut s = State {
+            money: 10.0,
+            materials: vec![ProductMaterial::new(
+                1,
+                None,
+                "shop".to_string(),
+                4.0,
+                Either::Left(1.0),
+                true,
+            )],
+            ..Default::default()
+        };
+
+        s.buy_material(0, 2);
+        assert_eq!(s.money, 2.0);
+
+        s.sell_material(0, 1);
+        assert_eq!(s.money, 4.0);
+        assert_eq!(s.materials[0].bought(), 2);
+
+        // initial and regenerated units are refunded nothing
+        s.materials[0].count += 1;
+        s.sell_material(0, 5);
+        assert_eq!(s.money, 6.0);
+        assert_eq!(s.materials[0].count(), 0);
+        assert_eq!(s.materials[0].bought(), 1);
+        assert_eq!(s.materials[0].refund(), 0.0);
+
+        // refunds are based on the price paid, not the current one
+        s.materials[0] = s.materials[0].clone().with_refund(Either::Left(2.0));
+        s.buy_material(0, 1);
+        s.materials[0].base_price = 10.0;
+        assert_eq!(s.materials[0].refund(), 4.0);
+    }
+
+    #[test]
+    fn upkeep() {
+        let state = |bankruptcy| {
+            State {
+                money: 3.0,
+                materials: vec![ProductMaterial::new(
+                    2,
+                    None,
+                    "shop".to_string(),
+                    4.0,
+                    Either::Left(1.0),
+                    true,
+                )
+                .with_upkeep(1.0)],
+                automations: vec![Automation::new(
+                    "buyer".to_string(),
+                    AutomationKind::Buy(0),
+                    None,
+                    vec![],
+                    vec![],
+                )
+                .with_upkeep(10.0)],
+                ..Default::default()
+            }
+            .with_bankruptcy(bankruptcy)
+        };
+
+        let mut s = state(Bankruptcy::PauseAutomations);
+        s.automations[0].activate();
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.money(), 1.0);
+        assert!(s.events().is_empty());
+
+        s.update(Duration::from_secs(1));
+        assert!(s.bankrupt());
+        assert_eq!(s.events(), &[Event::Bankrupt]);
+        s.update(Duration::from_secs(1));
+        assert!(s.events().is_empty());
+        s.money = 100.0;
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.materials[0].count(), 3);
+        assert_eq!(s.money(), 84.0);
+
+        // only the bought shops are sold off
+        let mut s = state(Bankruptcy::SellMaterials);
+        s.money = 11.0;
+        s.buy_material(0, 2);
+        s.update(Duration::from_secs(1));
+        assert!(!s.bankrupt());
+        assert_eq!(s.materials[0].count(), 3);
+        assert_eq!(s.events()[1], Event::MaterialSoldOff(0, 1));
+
+        s.update(Duration::from_secs(3));
+        assert!(s.bankrupt());
+        assert_eq!(s.materials[0].count(), 2);
+
+        // stock arriving while in debt is sold off too
+        s.materials[0].buy(4.0);
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.materials[0].count(), 2);
+        assert_eq!(s.events(), &[Event::MaterialSoldOff(0, 1)]);
+
+        let mut s = state(Bankruptcy::Lose);
+        s.update(Duration::from_secs(4));
+        assert!(s.lost());
+        assert_eq!(s.outcome(), Outcome::Lost(LoseReason::Bankruptcy));
+
+        // already in debt before paying the upkeep
+        let mut s = state(Bankruptcy::Lose);
+        s.money = -1.0;
+        s.update(Duration::from_secs(1));
+        assert!(s.lost());
+    }
+
+    #[test]
+    fn lose_conditions() {
+        let mut s = State {
+            materials: vec![ProductMaterial::new(
+                1,
+                None,
+                "lemon".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )
+            .with_decay(Decay::Amount(1.0))],
+            ..Default::default()
+        }
+        .with_lose_conditions(vec![
+            LoseCondition::new("never".to_string(), vec![]),
+            LoseCondition::new(
+                "out of lemons".to_string(),
+                vec![Condition::Compare(Stat::Material(0), Comparison::Eq, 0.0)],
+            ),
+        ]);
+        assert_eq!(s.outcome(), Outcome::InProgress);
+
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.outcome(), Outcome::Lost(LoseReason::Condition(1)));
+
+        // the game is over
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.time_played(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn stages() {
+        let mut s = State {
+            money: 5.0,
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![],
+                vec![],
+                vec![],
+                false,
+            )],
+            ..Default::default()
+        }
+        .with_stages(vec![
+            Stage::new(
+                "stand".to_string(),
+                Objective::new(vec![Condition::Money(10.0), Condition::Money(1.0)]),
+                vec![
+                    StageEffect::UnlockProduct(0),
+                    StageEffect::Demand(0, 2.0),
+                    StageEffect::SetMoney(0.0),
+                    StageEffect::Reveal("market".to_string()),
+                ],
+            ),
+            Stage::new(
+                "factory".to_string(),
+                Objective::new(vec![Condition::Money(100.0)]),
+                vec![],
+            ),
+        ]);
+        assert_eq!(s.current_stage().unwrap().name(), "stand");
+        assert_eq!(s.stage_progress(), 0.5);
+
+        s.money = 10.0;
+        s.update(Duration::ZERO);
+        assert_eq!(s.stage(), 1);
+        assert_eq!(s.events(), &[Event::StageCompleted(0)]);
+        assert!(s.products[0].active());
+        assert_eq!(s.products[0].demand(), 2.0);
+        assert_eq!(s.money(), 0.0);
+        assert!(s.revealed("market"));
+        assert!(!s.win());
+
+        s.money = 100.0;
+        s.update(Duration::ZERO);
+        assert!(s.current_stage().is_none());
+        assert!(s.win());
+
+        let mut s = State::default().with_stages(vec![Stage::new(
+            "stand".to_string(),
+            Objective::default(),
+            vec![StageEffect::SetProduct(0, 1)],
+        )]);
+        assert!(s.compile_conditions().is_err());
+    }
+
+    #[test]
+    fn post_win() {
+        let post_win: PostWin = serde_yaml::from_str("!NewObjective [!Money 20.0]").unwrap();
+        let mut s = State {
+            money: 10.0,
+            objective: Objective::new(vec![Condition::Money(10.0)]),
+            ..Default::default()
+        }
+        .with_post_win(post_win);
+
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.events(), &[Event::Won]);
+        assert_eq!(s.outcome(), Outcome::InProgress);
+        assert_eq!(s.wins()[0].time(), Duration::from_secs(1));
+
+        s.money = 20.0;
+        s.update(Duration::from_secs(1));
+        assert!(s.win() && s.finished());
+        assert_eq!(s.wins().len(), 2);
+
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.time_played(), Duration::from_secs(2));
+
+        let mut s = State {
+            money: 10.0,
+            objective: Objective::new(vec![Condition::Money(10.0)]),
+            ..Default::default()
+        }
+        .with_post_win(PostWin::Continue);
+        s.update(Duration::from_secs(1));
+        s.money = 0.0;
+        s.update(Duration::from_secs(1));
+        assert!(s.win() && !s.finished());
+        assert_eq!(s.time_played(), Duration::from_secs(2));
+        assert_eq!(s.wins()[0].money(), 10.0);
+    }
+
+    #[test]
+    fn prestige_objective() {
+        let mut s = State {
+            money: 10.0,
+            objective: Objective::new(vec![Condition::Money(10.0)]),
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![],
+                vec![],
+                vec![],
+                true,
+            )],
+            ..Default::default()
+        }
+        .with_post_win(PostWin::NewObjective(Objective::new(vec![
+            Condition::Money(20.0),
+        ])))
+        .with_currencies(vec![Currency::new("golden lemons".to_string(), 0.0)])
+        .with_prestige(
+            Prestige::new(0, "total_sold".to_string(), vec![Condition::Money(5.0)])
+                .with_reset(vec![ResetScope::Money]),
+        );
+        s.compile_conditions().unwrap();
+        s.record_reset_point();
+
+        s.update(Duration::ZERO);
+        assert_eq!(s.objective.win_condition(), &[Condition::Money(20.0)]);
+        assert!(matches!(s.post_win, PostWin::Stop));
+
+        s.products[0].sold = 5;
+        assert!(s.prestige());
+        assert_eq!(s.objective.win_condition(), &[Condition::Money(10.0)]);
+        assert!(matches!(s.post_win, PostWin::NewObjective(_)));
+
+        s.money = 10.0;
+        s.update(Duration::ZERO);
+        assert_eq!(s.outcome(), Outcome::InProgress);
+        assert_eq!(s.objective.win_condition(), &[Condition::Money(20.0)]);
+    }
+
+    #[test]
+    fn prestige() {
+        let mut s = State {
+            money: 1.0,
+            materials: vec![ProductMaterial::new(
+                1,
+                None,
+                "shop".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )],
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![],
+                vec![],
+                vec![],
+                true,
+            )],
+            perks: vec![
+                Perk::new(
+                    "golden lemons".to_string(),
+                    "".to_string(),
+                    vec![],
+                    vec![Quantity::Currency(0, 10.0)],
+                    (Quantity::Product(0, 2, None), PerkKind::Multiply),
+                )
+                .with_persistent(),
+                Perk::new(
+                    "bigger cups".to_string(),
+                    "".to_string(),
+                    vec![],
+                    vec![Quantity::Money(1.0)],
+                    (Quantity::Product(0, 2, None), PerkKind::Multiply),
+                ),
+            ],
+            ..Default::default()
+        }
+        .with_currencies(vec![Currency::new("golden lemons".to_string(), 0.0)])
+        .with_prestige(
+            Prestige::new(0, "total_sold".to_string(), vec![Condition::Money(5.0)]).with_reset(
+                vec![ResetScope::Money, ResetScope::Products, ResetScope::Perks],
+            ),
+        );
+        s.compile_conditions().unwrap();
+        s.record_reset_point();
+
+        s.products[0].sold = 50;
+        s.materials[0].count = 5;
+        assert_eq!(s.prestige_reward(), Some(50.0));
+        assert!(!s.prestige());
+
+        s.money = 6.0;
+        s.perks[1].unlock();
+        s.buy_perk(1);
+        assert!(s.prestige());
+        assert_eq!(s.events(), &[Event::Prestige(50.0)]);
+        assert_eq!(s.balance(Some(0)), 50.0);
+        assert_eq!(s.money(), 1.0);
+        assert_eq!(s.products[0].sold(), 0);
+        assert_eq!(s.materials[0].count(), 5);
+        assert!(!s.perks[1].active());
+        assert_eq!(s.lifetime().sold(), 50);
+        assert_eq!(s.stat(Stat::Prestiges), 1.0);
+
+        s.perks[0].unlock();
+        s.buy_perk(0);
+        s.money = 5.0;
+        s.products[0].sold = 1;
+        assert_eq!(s.prestige_reward(), Some(51.0));
+        assert!(s.prestige());
+        assert!(s.perks[0].active());
+
+        // compiling again doesn't move the reset point
+        s.money = 5.0;
+        s.compile_conditions().unwrap();
+        s.products[0].sold = 1;
+        assert!(s.prestige());
+        assert_eq!(s.money(), 1.0);
+
+        let saved = serde_yaml::to_string(&s.lifetime()).unwrap();
+        let lifetime: Lifetime = serde_yaml::from_str(&saved).unwrap();
+        assert_eq!(lifetime.prestiges(), 3);
+    }
+
+    #[test]
+    fn challenges() {
+        let mut s = State {
+            money: 1.0,
+            materials: vec![ProductMaterial::new(
+                0,
+                None,
+                "lemon".to_string(),
+                1.0,
+                Either::Left(10.0),
+                true,
+            )],
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![],
+                vec![],
+                vec![],
+                true,
+            )],
+            perks: vec![Perk::new(
+                "handmade".to_string(),
+                "".to_string(),
+                vec![Condition::Money(1e9)],
+                vec![],
+                (Quantity::Product(0, 2, None), PerkKind::Multiply),
+            )],
+            automations: vec![Automation::new(
+                "buyer".to_string(),
+                AutomationKind::Buy(0),
+                None,
+                vec![],
+                vec![],
+            )],
+            ..Default::default()
+        }
+        .with_challenges(vec![Challenge::new(
+            "handmade".to_string(),
+            vec![
+                ChallengeRule::NoAutomations,
+                ChallengeRule::MaterialGrowth(2.0),
+                ChallengeRule::Demand(0.5),
+            ],
+            Objective::new(vec![Condition::Money(10.0)]),
+            vec![0],
+        )])
+        .with_stages(vec![Stage::new(
+            "stand".to_string(),
+            Objective::new(vec![Condition::Money(3.0)]),
+            vec![StageEffect::Demand(0, 4.0)],
+        )]);
+        s.compile_conditions().unwrap();
+        s.record_reset_point();
+
+        s.automations[0].activate();
+        s.money = 5.0;
+        s.start_challenge(0);
+        assert_eq!(s.challenge(), Some(0));
+        assert_eq!(s.money(), 1.0);
+        assert!(!s.automations[0].active());
+        assert_eq!(s.materials[0].price_at(10), 100.0);
+        assert_eq!(s.demand(0), 0.5);
+
+        s.money = 3.0;
+        s.update(Duration::ZERO);
+        assert_eq!(s.stage(), 1);
+        assert_eq!(s.demand(0), 2.0);
+
+        s.money = 10.0;
+        s.update(Duration::ZERO);
+        assert_eq!(s.events(), &[Event::ChallengeCompleted(0)]);
+        assert_eq!(s.challenge(), None);
+        assert_eq!(s.stat(Stat::Challenge(0)), 1.0);
+        assert!(s.perks[0].active());
+        assert_eq!(s.materials[0].price_at(10), 10.0);
+        assert!(!s.can_start_challenge(0));
+    }
+
+    #[test]
+    fn perk_levels() {
+        let mut s = State {
+            money: 100.0,
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![],
+                vec![0],
+                vec![],
+                true,
+            )],
+            perks: vec![Perk::new(
+                "bigger cups".to_string(),
+                "".to_string(),
+                vec![],
+                vec![Quantity::Money(10.0)],
+                (Quantity::Product(0, 2, None), PerkKind::Multiply),
+            )
+            .with_levels(Some(3), Either::Left(2.0))],
+            ..Default::default()
+        };
+        s.compile_conditions().unwrap();
+        s.perks[0].unlock();
+
+        assert_eq!(s.buy_perk(0), 1);
+        assert_eq!(s.apply_product_perks(1, 0), 2);
+        assert_eq!(s.perks[0].next_price()[0].quantity(), 20.0);
+
+        assert_eq!(s.buy_perk(0), 2);
+        assert_eq!(s.buy_perk(0), 3);
+        assert_eq!(s.money(), 30.0);
+        assert_eq!(s.apply_product_perks(1, 0), 8);
+        assert!(s.perks[0].maxed());
+
+        s.perks[0].level = 0;
+        s.money = 5.0;
+        assert_eq!(s.buy_perk(0), 0);
+        assert!(!s.perks[0].active());
+    }
+
+    #[test]
+    fn perk_scopes() {
+        let product = |name: &str, dependencies| {
+            Product::new(
+                name.to_string(),
+                Some(1.0),
+                dependencies,
+                vec![],
+                vec![],
+                true,
+            )
+        };
+        let perk = |scope, perk: (Quantity, PerkKind)| {
+            Perk::new("".to_string(), "".to_string(), vec![], vec![], perk).with_scope(scope)
+        };
+        let mut s = State {
+            materials: vec![ProductMaterial::new(
+                0,
+                None,
+                "lemon".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )],
+            products: vec![
+                product("lemonade", vec![Relation::needs(Quantity::Material(0, 4))])
+                    .with_tags(vec!["drink".to_string()]),
+                product("cookie", vec![]),
+                product("juice", vec![]).with_tags(vec!["drink".to_string()]),
+            ],
+            perks: vec![
+                perk(
+                    PerkScope::AllProducts,
+                    (Quantity::Product(0, 2, None), PerkKind::Multiply),
+                ),
+                perk(
+                    PerkScope::Tag("drink".to_string()),
+                    (Quantity::Product(0, 1, None), PerkKind::Add),
+                ),
+                perk(
+                    PerkScope::Material(0),
+                    (Quantity::Material(0, 2), PerkKind::Divide),
+                ),
+                perk(
+                    PerkScope::Automated,
+                    (Quantity::Product(0, 3, None), PerkKind::Multiply),
+                ),
+            ],
+            automations: vec![Automation::new(
+                "juicer".to_string(),
+                AutomationKind::Build(2),
+                None,
+                vec![],
+                vec![],
+            )],
+            ..Default::default()
+        };
+        s.compile_conditions().unwrap();
+        for perk in s.perks.iter_mut() {
+            perk.activate();
+        }
+
+        assert_eq!(s.product_perks(0), vec![0, 1, 2]);
+        assert_eq!(s.product_perks(1), vec![0]);
+        assert_eq!(s.product_perks(2), vec![0, 1, 3]);
+
+        assert_eq!(s.apply_product_perks(1, 1), 2);
+        assert_eq!(s.apply_product_perks(1, 2), 12);
+        let lemons = s.apply_perk(0, Relation::needs(Quantity::Material(0, 4)));
+        assert_eq!(lemons.quantity().quantity(), 2.0);
+    }
+
+    #[test]
+    fn stat_perks() {
+        let perk = |target: PerkTarget, kind| {
+            let mut perk = Perk::new(
+                "".to_string(),
+                "".to_string(),
+                vec![],
+                vec![],
+                (target, kind),
+            );
+            perk.activate();
+            perk
+        };
+        let automation = |kind| {
+            Automation::new(
+                "".to_string(),
+                kind,
+                Some(Timer::new(Duration::from_secs(2))),
+                vec![],
+                vec![],
+            )
+        };
+        let mut s = State {
+            money: 100.0,
+            materials: vec![ProductMaterial::new(
+                0,
+                Some(10),
+                "lemon".to_string(),
+                1.0,
+                Either::Left(10.0),
+                true,
+            )],
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(2.0),
+                vec![],
+                vec![0, 1],
+                vec![],
+                true,
+            )],
+            perks: vec![
+                perk(PerkTarget::SellPrice(1.5), PerkKind::Multiply),
+                perk(PerkTarget::Interest(2.0), PerkKind::Set),
+                perk(PerkTarget::MaterialLimit(0, 5.0), PerkKind::Add),
+                perk(PerkTarget::MaterialGrowth(0, 0.5), PerkKind::Multiply),
+                perk(PerkTarget::AutomationTimer(0, 2.0), PerkKind::Divide)
+                    .with_scope(PerkScope::Automations),
+                perk(PerkTarget::AutomationBatch(1, 3.0), PerkKind::Set),
+            ],
+            automations: vec![
+                automation(AutomationKind::Build(0)),
+                automation(AutomationKind::Buy(0)),
+            ],
+            ..Default::default()
+        };
+        s.compile_conditions().unwrap();
+
+        let recipe = s.effective_recipe(0);
+        assert_eq!(recipe.base_price(), Some(2.0));
+        assert_eq!(recipe.sell_price(), Some(3.0));
+        assert_eq!(recipe.interest(), 2.0);
+
+        assert_eq!(s.stat(Stat::MaterialLimit(0)), 15.0);
+        assert_eq!(s.material_price_at(0, 20), 10.0);
+        assert_eq!(s.stat(Stat::AutomationTimer(0)), 1.0);
+        assert_eq!(s.stat(Stat::AutomationTimer(1)), 1.0);
+        assert_eq!(s.automation_batch(0), 1);
+        assert_eq!(s.stat(Stat::AutomationBatch(1)), 3.0);
+
+        s.automations[1].activate();
+        s.update(Duration::from_secs(1));
+        assert_eq!(s.materials[0].count(), 3);
+
+        s.perks[4].perk.0 = PerkTarget::AutomationTimer(0, 0.0);
+        assert_eq!(s.automation_timer(0), Some(Duration::MAX));
+        s.update(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn perk_stacking() {
+        let perk = |kind, cnt| {
+            let mut perk = Perk::new(
+                "".to_string(),
+                "".to_string(),
+                vec![],
+                vec![],
+                (Quantity::Product(0, cnt, None), kind),
+            );
+            perk.activate();
+            perk
+        };
+        let s = State {
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![],
+                vec![0, 1, 2, 3],
+                vec![],
+                true,
+            )],
+            perks: vec![
+                perk(PerkKind::Multiply, 2),
+                perk(PerkKind::Add, 3),
+                perk(PerkKind::Set, 1),
+                perk(PerkKind::Set, 10).with_priority(-1),
+            ],
+            ..Default::default()
+        };
+
+        let breakdown = s.breakdown(PerkValue::Quantity(0, Quantity::Product(0, 1, None)));
+        assert_eq!(breakdown.base(), 1.0);
+        assert_eq!(
+            breakdown
+                .steps()
+                .iter()
+                .map(|step| step.perk())
+                .collect::<Vec<_>>(),
+            vec![3, 2, 1, 0]
+        );
+        assert_eq!(breakdown.steps()[3].contribution(), 4.0);
+        assert_eq!(breakdown.value(), 8.0);
+        assert_eq!(s.apply_product_perks(1, 0), 8);
+    }
+
+    #[test]
+    fn perk_tree() {
+        let perk = |requires: Vec<usize>| {
+            Perk::new(
+                "".to_string(),
+                "".to_string(),
+                vec![],
+                vec![Quantity::Money(1.0)],
+                (Quantity::Money(1.0), PerkKind::Add),
+            )
+            .with_requires(requires)
+        };
+        let mut s = State {
+            money: 10.0,
+            perks: vec![
+                perk(vec![]),
+                perk(vec![0]),
+                perk(vec![0]).with_excludes(vec![1]),
+                perk(vec![1]),
+            ],
+            ..Default::default()
+        };
+        s.compile_conditions().unwrap();
+
+        s.update(Duration::ZERO);
+        assert!(s.perks[0].unlocked());
+        assert!(!s.perks[1].unlocked());
+
+        s.buy_perk(0);
+        s.update(Duration::ZERO);
+        assert!(s.perks[1].unlocked() && s.perks[2].unlocked());
+
+        s.buy_perk(1);
+        assert!(s.perk_locked(2));
+        assert!(!s.perks[2].unlocked());
+        s.update(Duration::ZERO);
+        assert!(!s.perks[2].unlocked());
+        assert!(s.perks[3].unlocked());
+
+        let tree = s.perk_tree();
+        assert_eq!(tree[0].children(), &vec![1, 2]);
+        assert_eq!(tree[1].exclusive(), &vec![2]);
+        assert_eq!(tree[3].depth(), 2);
+        assert!(tree[2].locked());
+    }
+
+    #[test]
+    fn condition_tree() {
+        let conds: Vec<Condition> = serde_yaml::from_str(
+            r#"
+- !Any [!Product [0, 10, Sold], !Material [0, 5]]
+- !Not [!Compare [!Perk 0, ==, 1]]
+- !Compare [Money, "<", 10]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            conds[2],
+            Condition::Compare(Stat::Money, Comparison::Lt, 10.0)
+        );
+
+        let mut s = State {
+            money: 5.0,
+            materials: vec![ProductMaterial::new(
+                5,
+                None,
+                "shop".to_string(),
+                1.0,
+                Either::Left(1.0),
+                true,
+            )],
+            products: vec![Product::new(
+                "lemonade".to_string(),
+                Some(1.0),
+                vec![],
+                vec![],
+                vec![],
+                true,
+            )],
+            perks: vec![Perk::new(
+                "perk".to_string(),
+                "".to_string(),
+                vec![],
+                vec![],
+                (Quantity::Money(1.0), PerkKind::Set),
+            )],
+            ..Default::default()
+        };
+        assert!(s.check_conditions(&conds));
+
+        s.perks[0].activate();
+        assert!(!s.check_conditions(&conds));
+        assert_eq!(s.stat(Stat::Perk(0)), 1.0);
+    }
 }
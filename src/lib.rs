@@ -1,13 +1,21 @@
 mod serde;
 
+pub mod analysis;
+pub mod export;
 pub mod incremental;
 pub mod loader;
+pub mod parser;
+pub mod planner;
 pub mod timer;
 pub mod types;
 
 pub mod prelude {
+    pub use crate::analysis::*;
+    pub use crate::export::*;
     pub use crate::incremental::*;
     pub use crate::loader::*;
+    pub use crate::parser::*;
+    pub use crate::planner::*;
     pub use crate::timer::Timer;
     pub use crate::types::*;
 }
@@ -14,17 +14,22 @@ pub fn load(file_path: &Path) -> Result<State> {
     let file = File::open(file_path)?;
     let reader = BufReader::new(file);
 
-    if ext == "yaml" || ext == "yml" {
+    let mut state: State = if ext == "yaml" || ext == "yml" {
         match serde_yaml::from_reader(reader) {
-            Ok(state) => Ok(state),
-            Err(err) => Err(anyhow!("{}", err.to_string())),
+            Ok(state) => state,
+            Err(err) => return Err(anyhow!("{}", err.to_string())),
         }
     } else if ext == "json" {
         match serde_json::from_reader(reader) {
-            Ok(state) => Ok(state),
-            Err(err) => Err(anyhow!("{}", err.to_string())),
+            Ok(state) => state,
+            Err(err) => return Err(anyhow!("{}", err.to_string())),
         }
     } else {
-        Err(anyhow!("Unsupported extension {}", ext))
-    }
+        return Err(anyhow!("Unsupported extension {}", ext));
+    };
+
+    state.compile_conditions()?;
+    state.record_reset_point();
+
+    Ok(state)
 }
@@ -0,0 +1,446 @@
+use anyhow::{anyhow, Error, Result};
+
+use crate::incremental::{Comparison, Condition, ProductConditionKind, Stat, State};
+
+// Parses a condition written as an expression into a [`Condition`].
+// Objects may be referred to either by their 0-based index or by their
+// name, which is matched case-insensitively and may be quoted.
+//
+// Grammar:
+// ```
+// expr       := and ( ("||" | "or") and )*
+// and        := unary ( ("&&" | "and") unary )*
+// unary      := ("!" | "not") unary | "(" expr ")" | comparison
+// comparison := stat [ cmp number ] | number cmp stat
+// cmp        := "<" | "<=" | "==" | "!=" | ">=" | ">"
+// stat       := "money" | "time" | "badges" | "prestiges" | "currency" "(" currency ")"
+//             | ("count" | "sold" | "produced" | "decayed") "(" product ")"
+//             | ("material" | "bought" | "decayed_material") "(" material ")"
+//             | "badge" "(" badge ")" | "perk" "(" perk ")" | "automation" "(" automation ")"
+//             | "challenge" "(" challenge ")"
+// ```
+// A stat without a comparison is met when the stat is not zero,
+// f.e `!perk(Lemonficcient)` is met when the perk is not bought.
+//
+// # Example
+// ```
+// sold(lemonade) >= 200 && (money > 1e4 || material("Shop") >= 10)
+// ```
+pub fn parse_condition(expr: &str, state: &State) -> Result<Condition> {
+    let mut parser = Parser {
+        src: expr,
+        pos: 0,
+        state,
+    };
+
+    let cond = parser.or()?;
+    parser.skip_whitespace();
+    if parser.pos < expr.len() {
+        return Err(parser.error("unexpected input"));
+    }
+
+    Ok(cond)
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    state: &'a State,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> Error {
+        anyhow!(
+            "{} at position {} in condition '{}'",
+            msg,
+            self.pos,
+            self.src
+        )
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", token)))
+        }
+    }
+
+    fn peek_ident(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+
+        if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            &rest[..len]
+        } else {
+            ""
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_ident().eq_ignore_ascii_case(keyword) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition> {
+        let mut conds = vec![self.and()?];
+        while self.eat("||") || self.eat_keyword("or") {
+            conds.push(self.and()?);
+        }
+
+        Ok(if conds.len() == 1 {
+            conds.pop().unwrap()
+        } else {
+            Condition::Any(conds)
+        })
+    }
+
+    fn and(&mut self) -> Result<Condition> {
+        let mut conds = vec![self.unary()?];
+        while self.eat("&&") || self.eat_keyword("and") {
+            conds.push(self.unary()?);
+        }
+
+        Ok(if conds.len() == 1 {
+            conds.pop().unwrap()
+        } else {
+            Condition::All(conds)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Condition> {
+        self.skip_whitespace();
+        let negated = !self.rest().starts_with("!=") && self.eat("!");
+        if negated || self.eat_keyword("not") {
+            return Ok(Condition::Not(vec![self.unary()?]));
+        }
+
+        if self.eat("(") {
+            let cond = self.or()?;
+            self.expect(")")?;
+            return Ok(cond);
+        }
+
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Condition> {
+        if let Some(value) = self.number() {
+            let cmp = self
+                .comparison_op()
+                .ok_or_else(|| self.error("expected comparison"))?;
+            let stat = self.stat()?;
+
+            // `value < stat` is the same as `stat > value`
+            let cmp = match cmp {
+                Comparison::Lt => Comparison::Gt,
+                Comparison::Le => Comparison::Ge,
+                Comparison::Ge => Comparison::Le,
+                Comparison::Gt => Comparison::Lt,
+                cmp => cmp,
+            };
+
+            return Ok(Condition::Compare(stat, cmp, value));
+        }
+
+        let stat = self.stat()?;
+        match self.comparison_op() {
+            Some(cmp) => {
+                let value = self.number().ok_or_else(|| self.error("expected number"))?;
+                Ok(Condition::Compare(stat, cmp, value))
+            }
+            None => Ok(Condition::Compare(stat, Comparison::Ne, 0.0)),
+        }
+    }
+
+    fn comparison_op(&mut self) -> Option<Comparison> {
+        let ops = [
+            (">=", Comparison::Ge),
+            ("<=", Comparison::Le),
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            (">", Comparison::Gt),
+            ("<", Comparison::Lt),
+        ];
+
+        ops.into_iter()
+            .find(|(token, _)| self.eat(token))
+            .map(|(_, cmp)| cmp)
+    }
+
+    fn number(&mut self) -> Option<f64> {
+        self.skip_whitespace();
+        let rest = self.rest();
+
+        let mut len = 0;
+        let mut prev = ' ';
+        for (i, c) in rest.char_indices() {
+            let sign = (c == '-' || c == '+') && (i == 0 || prev == 'e' || prev == 'E');
+            if !(c.is_ascii_digit() || c == '.' || c == '_' || c == 'e' || c == 'E' || sign) {
+                break;
+            }
+            // A number can't start with an exponent, that's an identifier
+            if i == 0 && (c == 'e' || c == 'E') {
+                break;
+            }
+            prev = c;
+            len = i + c.len_utf8();
+        }
+
+        let value = rest[..len].replace('_', "").parse().ok()?;
+        self.pos += len;
+
+        Some(value)
+    }
+
+    // Reads the argument of a stat between the parentheses.
+    fn argument(&mut self) -> Result<&'a str> {
+        self.expect("(")?;
+
+        let rest = self.rest();
+        let len = rest.find(')').ok_or_else(|| self.error("expected ')'"))?;
+        self.pos += len + 1;
+
+        let arg = rest[..len].trim();
+        Ok(arg
+            .strip_prefix('"')
+            .and_then(|arg| arg.strip_suffix('"'))
+            .or_else(|| {
+                arg.strip_prefix('\'')
+                    .and_then(|arg| arg.strip_suffix('\''))
+            })
+            .unwrap_or(arg))
+    }
+
+    fn resolve<'n>(
+        &mut self,
+        what: &str,
+        names: impl ExactSizeIterator<Item = &'n str>,
+    ) -> Result<usize> {
+        let arg = self.argument()?;
+        let len = names.len();
+        let mut names = names;
+
+        if let Ok(id) = arg.parse::<usize>() {
+            if id < len {
+                return Ok(id);
+            }
+
+            return Err(self.error(&format!(
+                "{} index {} is out of range, there are {} of them",
+                what, id, len
+            )));
+        }
+
+        names
+            .position(|name| name.eq_ignore_ascii_case(arg))
+            .ok_or_else(|| self.error(&format!("unknown {} '{}'", what, arg)))
+    }
+
+    fn stat(&mut self) -> Result<Stat> {
+        let state = self.state;
+        let ident = self.peek_ident().to_lowercase();
+        if ident.is_empty() {
+            return Err(self.error("expected stat"));
+        }
+        self.pos += ident.len();
+
+        let products = || state.products().iter().map(|p| p.name());
+        let materials = || state.materials().iter().map(|m| m.name());
+
+        let stat = match ident.as_str() {
+            "money" => Stat::Money,
+            "currency" => Stat::Currency(
+                self.resolve("currency", state.currencies().iter().map(|c| c.name()))?,
+            ),
+            "time" => Stat::TimePlayed,
+            "badges" => Stat::Badges,
+            "prestiges" => Stat::Prestiges,
+            "count" => Stat::Product(
+                self.resolve("product", products())?,
+                ProductConditionKind::Count,
+            ),
+            "sold" => Stat::Product(
+                self.resolve("product", products())?,
+                ProductConditionKind::Sold,
+            ),
+            "produced" => Stat::Product(
+                self.resolve("product", products())?,
+                ProductConditionKind::Produced,
+            ),
+            "material" => Stat::Material(self.resolve("material", materials())?),
+            "bought" => Stat::MaterialBought(self.resolve("material", materials())?),
+            "decayed" => Stat::ProductDecayed(self.resolve("product", products())?),
+            "decayed_material" => Stat::MaterialDecayed(self.resolve("material", materials())?),
+            "badge" => Stat::Badge(self.resolve("badge", state.badges().iter().map(|b| b.name()))?),
+            "perk" => Stat::Perk(self.resolve("perk", state.perks().iter().map(|p| p.name()))?),
+            "automation" => Stat::Automation(
+                self.resolve("automation", state.automations().iter().map(|a| a.name()))?,
+            ),
+            "challenge" => Stat::Challenge(
+                self.resolve("challenge", state.challenges().iter().map(|c| c.name()))?,
+            ),
+            "sell_price" => Stat::SellPrice(self.resolve("product", products())?),
+            "interest" => Stat::Interest(self.resolve("product", products())?),
+            "price" => Stat::MaterialPrice(self.resolve("material", materials())?),
+            "limit" => Stat::MaterialLimit(self.resolve("material", materials())?),
+            "timer" => Stat::AutomationTimer(
+                self.resolve("automation", state.automations().iter().map(|a| a.name()))?,
+            ),
+            "batch" => Stat::AutomationBatch(
+                self.resolve("automation", state.automations().iter().map(|a| a.name()))?,
+            ),
+            _ => {
+                self.pos -= ident.len();
+                return Err(self.error(&format!("unknown stat '{}'", ident)));
+            }
+        };
+
+        Ok(stat)
+    }
+}
+
+impl Condition {
+    // Parses the condition from an expression. See [`parse_condition`]
+    pub fn parse(expr: &str, state: &State) -> Result<Condition> {
+        parse_condition(expr, state)
+    }
+
+    // Replaces all the conditions written as expressions with their parsed form
+    // and makes sure all the objects referred to by the condition exist.
+    pub(crate) fn compile(&mut self, state: &State) -> Result<()> {
+        match self {
+            Condition::Expr(expr) => {
+                *self = parse_condition(expr, state)?;
+                Ok(())
+            }
+            Condition::All(conds) | Condition::Any(conds) | Condition::Not(conds) => {
+                conds.iter_mut().try_for_each(|c| c.compile(state))
+            }
+            _ => {
+                let (stat, _, _) = self.comparison().unwrap();
+                let (what, id, len) = match stat {
+                    Stat::Currency(id) => ("currency", id, state.currencies().len()),
+                    Stat::Material(id)
+                    | Stat::MaterialBought(id)
+                    | Stat::MaterialDecayed(id)
+                    | Stat::MaterialPrice(id)
+                    | Stat::MaterialLimit(id) => ("material", id, state.materials().len()),
+                    Stat::Product(id, _)
+                    | Stat::ProductDecayed(id)
+                    | Stat::SellPrice(id)
+                    | Stat::Interest(id) => ("product", id, state.products().len()),
+                    Stat::Badge(id) => ("badge", id, state.badges().len()),
+                    Stat::Perk(id) => ("perk", id, state.perks().len()),
+                    Stat::Automation(id)
+                    | Stat::AutomationTimer(id)
+                    | Stat::AutomationBatch(id) => ("automation", id, state.automations().len()),
+                    Stat::Challenge(id) => ("challenge", id, state.challenges().len()),
+                    Stat::Money | Stat::Badges | Stat::TimePlayed | Stat::Prestiges => {
+                        return Ok(())
+                    }
+                };
+
+                if id < len {
+                    Ok(())
+                } else {
+                    Err(anyhow!(
+                        "condition refers to {} {}, but there are only {} of them",
+                        what,
+                        id,
+                        len
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::incremental::{Comparison, Condition, ProductConditionKind, Stat};
+    use crate::loader::load;
+
+    #[test]
+    fn parse_expressions() {
+        let state = load(Path::new("res/lemonstand.yml")).unwrap();
+
+        assert_eq!(
+            Condition::parse("sold(lemonade) >= 200 && money > 1e4", &state).unwrap(),
+            Condition::All(vec![
+                Condition::Compare(
+                    Stat::Product(0, ProductConditionKind::Sold),
+                    Comparison::Ge,
+                    200.0
+                ),
+                Condition::Compare(Stat::Money, Comparison::Gt, 10000.0),
+            ])
+        );
+
+        assert_eq!(
+            Condition::parse("not (perk(\"Lemonficcient\") or 10 < bought(1))", &state).unwrap(),
+            Condition::Not(vec![Condition::Any(vec![
+                Condition::Compare(Stat::Perk(0), Comparison::Ne, 0.0),
+                Condition::Compare(Stat::MaterialBought(1), Comparison::Gt, 10.0),
+            ])])
+        );
+
+        assert_eq!(
+            Condition::parse("!badge(King of the lemonade trade) || time < 60", &state).unwrap(),
+            Condition::Any(vec![
+                Condition::Not(vec![Condition::Compare(
+                    Stat::Badge(0),
+                    Comparison::Ne,
+                    0.0
+                )]),
+                Condition::Compare(Stat::TimePlayed, Comparison::Lt, 60.0),
+            ])
+        );
+
+        assert!(Condition::parse("sold(orange) > 1", &state).is_err());
+        assert!(Condition::parse("sold(5) > 1", &state).is_err());
+        assert!(Condition::parse("money >", &state).is_err());
+        assert!(Condition::parse("money > 1 money", &state).is_err());
+    }
+
+    #[test]
+    fn compile_on_load() {
+        let state = load(Path::new("res/lemonstand.yml")).unwrap();
+        assert_eq!(
+            state.perks()[0].condition()[0],
+            Condition::Compare(
+                Stat::Product(0, ProductConditionKind::Sold),
+                Comparison::Ge,
+                100.0
+            )
+        );
+    }
+}
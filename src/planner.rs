@@ -0,0 +1,635 @@
+use std::collections::BTreeMap;
+
+use derive_getters::Getters;
+
+use crate::incremental::{Quantity, RelationKind, State};
+use crate::types::{Count, CurrencyId, Price, ProductId, ProductMaterialId};
+
+// Cost of buying the missing amount of a material.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaterialCost {
+    #[doc = "Amount of the material's currency needed at the current growth prices."]
+    Known(Price),
+
+    #[doc = "The material's limit doesn't allow buying the missing amount."]
+    OverLimit,
+
+    #[doc = "Too costly to compute or to represent, f.e when the price grows by"]
+    #[doc = "an expression and too much of the material is missing."]
+    Unknown,
+}
+
+// Amount of a material needed in order to execute a [`Plan`].
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct MaterialRequirement {
+    #[getter(copy)]
+    material: ProductMaterialId,
+
+    #[doc = "Amount needed, both consumed and needed to be present during construction."]
+    #[getter(copy)]
+    required: Count,
+
+    #[doc = "Amount currently present."]
+    #[getter(copy)]
+    available: Count,
+
+    #[doc = "Amount yielded as a byproduct by the constructions in the plan."]
+    #[getter(copy)]
+    from_byproducts: Count,
+
+    #[getter(copy)]
+    missing: Count,
+
+    #[doc = "Currency the material is bought with. None for money."]
+    #[getter(copy)]
+    currency: Option<CurrencyId>,
+
+    #[doc = "Cost of buying the missing amount."]
+    #[getter(copy)]
+    cost: MaterialCost,
+}
+
+// Amount of a declared currency needed in order to execute a [`Plan`].
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct CurrencyRequirement {
+    #[getter(copy)]
+    currency: CurrencyId,
+
+    #[doc = "Amount needed for both the constructions and buying the missing materials."]
+    #[getter(copy)]
+    required: Price,
+
+    #[doc = "Amount currently present."]
+    #[getter(copy)]
+    available: Price,
+
+    #[getter(copy)]
+    missing: Price,
+}
+
+// Intermediate product needed in order to execute a [`Plan`].
+#[derive(Copy, Clone, Debug, Getters)]
+pub struct ProductRequirement {
+    #[getter(copy)]
+    product: ProductId,
+
+    #[doc = "Amount needed by the other products in the plan."]
+    #[getter(copy)]
+    required: Count,
+
+    #[doc = "Amount taken from the current stock."]
+    #[getter(copy)]
+    from_stock: Count,
+
+    #[doc = "Amount taken from the byproducts of other constructions in the plan."]
+    #[getter(copy)]
+    from_byproducts: Count,
+
+    #[doc = "Number of constructions needed to make up for the rest."]
+    #[getter(copy)]
+    builds: Count,
+}
+
+// Bill of materials for building a certain amount of a product,
+// taking into account the whole dependency graph and the active perks.
+//
+// See [`State::plan`]
+#[derive(Clone, Debug, Getters)]
+pub struct Plan {
+    #[getter(copy)]
+    product: ProductId,
+
+    #[doc = "Amount of the product the plan is for."]
+    #[getter(copy)]
+    count: Count,
+
+    #[doc = "Number of constructions of the product needed."]
+    #[getter(copy)]
+    builds: Count,
+
+    #[doc = "Intermediate products needed, sorted by id."]
+    products: Vec<ProductRequirement>,
+
+    #[doc = "Materials needed, sorted by id."]
+    materials: Vec<MaterialRequirement>,
+
+    #[doc = "Money consumed by all the constructions."]
+    #[getter(copy)]
+    money: Price,
+
+    #[doc = "Declared currencies needed, sorted by id."]
+    currencies: Vec<CurrencyRequirement>,
+
+    #[doc = "Money needed to buy all the missing materials bought with money."]
+    #[doc = "Infinite if the cost of one of them is unknown."]
+    #[getter(copy)]
+    missing_cost: Price,
+
+    #[doc = "Money missing for both the constructions and buying the missing materials."]
+    #[getter(copy)]
+    missing_money: Price,
+
+    #[doc = "Products that can't be built, either because of circular dependencies"]
+    #[doc = "or because the active perks don't let them yield the needed amount."]
+    unbuildable: Vec<ProductId>,
+}
+
+impl Plan {
+    // Iterates over the materials that are not present in the needed amount.
+    pub fn missing(&self) -> impl Iterator<Item = &MaterialRequirement> {
+        self.materials.iter().filter(|m| m.missing > 0)
+    }
+
+    // Whether the plan can be executed at all, given enough money.
+    // Materials of unknown cost don't make the plan infeasible, only expensive.
+    pub fn feasible(&self) -> bool {
+        self.unbuildable.is_empty()
+            && self
+                .materials
+                .iter()
+                .all(|m| m.cost != MaterialCost::OverLimit)
+    }
+
+    // Whether everything needed for the plan is already present.
+    pub fn ready(&self) -> bool {
+        self.feasible()
+            && self.missing().next().is_none()
+            && self.missing_money <= 0.0
+            && self.currencies.iter().all(|c| c.missing <= 0.0)
+    }
+}
+
+struct Planner<'a> {
+    state: &'a State,
+    stack: Vec<ProductId>,
+    builds: BTreeMap<ProductId, Count>,
+    required: BTreeMap<ProductId, Count>,
+    from_stock: BTreeMap<ProductId, Count>,
+    from_byproducts: BTreeMap<ProductId, Count>,
+    byproducts: BTreeMap<ProductId, Count>,
+    material_byproducts: BTreeMap<ProductMaterialId, Count>,
+    consumed: BTreeMap<ProductMaterialId, Count>,
+    present: BTreeMap<ProductMaterialId, Count>,
+    money: Price,
+    money_present: Price,
+    currencies: BTreeMap<CurrencyId, Price>,
+    currencies_present: BTreeMap<CurrencyId, Price>,
+    unbuildable: Vec<ProductId>,
+}
+
+impl<'a> Planner<'a> {
+    fn new(state: &'a State) -> Self {
+        Self {
+            state,
+            stack: Vec::new(),
+            builds: BTreeMap::new(),
+            required: BTreeMap::new(),
+            from_stock: BTreeMap::new(),
+            from_byproducts: BTreeMap::new(),
+            byproducts: BTreeMap::new(),
+            material_byproducts: BTreeMap::new(),
+            consumed: BTreeMap::new(),
+            present: BTreeMap::new(),
+            money: 0.0,
+            money_present: 0.0,
+            currencies: BTreeMap::new(),
+            currencies_present: BTreeMap::new(),
+            unbuildable: Vec::new(),
+        }
+    }
+
+    // Smallest number of constructions that yield at least `cnt` of the product
+    // regardless of luck, see [`State::guaranteed_yield`].
+    fn builds_needed(&self, id: ProductId, cnt: Count) -> Option<Count> {
+        if cnt == 0 {
+            return Some(0);
+        }
+
+        let yields = |builds| self.state.guaranteed_yield(builds, id);
+
+        let mut hi: Count = 1;
+        while yields(hi) < cnt {
+            hi = hi.checked_mul(2)?;
+        }
+
+        let mut lo = hi / 2;
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if yields(mid) >= cnt {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+
+        Some(hi)
+    }
+
+    fn mark_unbuildable(&mut self, id: ProductId) {
+        if !self.unbuildable.contains(&id) {
+            self.unbuildable.push(id);
+        }
+    }
+
+    // Plans the constructions of `cnt` amount of a product and
+    // returns the number of constructions needed.
+    fn produce(&mut self, id: ProductId, cnt: Count) -> Count {
+        if self.stack.contains(&id) {
+            self.mark_unbuildable(id);
+            return 0;
+        }
+
+        let builds = match self.builds_needed(id, cnt) {
+            Some(builds) => builds,
+            None => {
+                self.mark_unbuildable(id);
+                return 0;
+            }
+        };
+
+        if builds == 0 {
+            return 0;
+        }
+
+        self.stack.push(id);
+
+        for rel in self.state.products()[id].dependencies().iter() {
+            let rel = self.state.apply_perk(id, *rel);
+            let consumed = rel.kind() == RelationKind::Consumes;
+
+            match *rel.quantity() {
+                Quantity::Money(money) => {
+                    if consumed {
+                        self.money += money * builds as f64;
+                    } else {
+                        self.money_present = self.money_present.max(money);
+                    }
+                }
+                Quantity::Currency(id, amount) => {
+                    if consumed {
+                        *self.currencies.entry(id).or_default() += amount * builds as f64;
+                    } else {
+                        let present = self.currencies_present.entry(id).or_default();
+                        *present = present.max(amount);
+                    }
+                }
+                Quantity::Material(id, cnt) => {
+                    if consumed {
+                        let total = self.consumed.entry(id).or_default();
+                        *total = total.saturating_add(cnt.saturating_mul(builds));
+                    } else {
+                        let present = self.present.entry(id).or_default();
+                        *present = (*present).max(cnt);
+                    }
+                }
+                Quantity::Product(id, cnt, _) => {
+                    if consumed {
+                        self.require_product(id, cnt.saturating_mul(builds));
+                    } else {
+                        self.require_present_product(id, cnt);
+                    }
+                }
+            }
+        }
+
+        self.stack.pop();
+
+        // Byproducts are available only after the constructions are done,
+        // so they may be used by the products depending on this one.
+        // Money and currencies yielded are not taken into account.
+        for output in self.state.products()[id].outputs().iter() {
+            match self.state.apply_output_perks(builds, id, *output) {
+                Quantity::Material(id, cnt) => {
+                    let total = self.material_byproducts.entry(id).or_default();
+                    *total = total.saturating_add(cnt);
+                }
+                Quantity::Product(id, cnt, _) => {
+                    let total = self.byproducts.entry(id).or_default();
+                    *total = total.saturating_add(cnt);
+                }
+                Quantity::Money(_) | Quantity::Currency(_, _) => {}
+            }
+        }
+
+        builds
+    }
+
+    // Takes as much as possible of a consumed product from the stock and
+    // the byproducts of the planned constructions and plans the constructions for the rest.
+    fn require_product(&mut self, id: ProductId, cnt: Count) {
+        let required = self.required.entry(id).or_default();
+        *required = required.saturating_add(cnt);
+
+        let stock = self.state.products()[id].count();
+        let taken = self.from_stock.entry(id).or_default();
+        let take = cnt.min(stock - *taken);
+        *taken += take;
+
+        let cnt = cnt - take;
+        let pool = self.byproducts.entry(id).or_default();
+        let take = cnt.min(*pool);
+        *pool -= take;
+        *self.from_byproducts.entry(id).or_default() += take;
+
+        self.build_missing(id, cnt - take);
+    }
+
+    // Plans the constructions of a product that only needs to be present.
+    fn require_present_product(&mut self, id: ProductId, cnt: Count) {
+        let stock = self.state.products()[id].count();
+        let missing = cnt.saturating_sub(stock);
+        if missing == 0 {
+            return;
+        }
+
+        let required = self.required.entry(id).or_default();
+        *required = required.saturating_add(missing);
+
+        self.build_missing(id, missing);
+    }
+
+    fn build_missing(&mut self, id: ProductId, missing: Count) {
+        if missing == 0 {
+            return;
+        }
+
+        let builds = self.produce(id, missing);
+        let total = self.builds.entry(id).or_default();
+        *total = total.saturating_add(builds);
+    }
+
+    fn material_requirement(&self, id: ProductMaterialId) -> MaterialRequirement {
+        let m = &self.state.materials()[id];
+        let required = self
+            .consumed
+            .get(&id)
+            .copied()
+            .unwrap_or(0)
+            .saturating_add(self.present.get(&id).copied().unwrap_or(0));
+        let from_byproducts = self
+            .material_byproducts
+            .get(&id)
+            .copied()
+            .unwrap_or(0)
+            .min(required.saturating_sub(m.count()));
+        let missing = required.saturating_sub(m.count()) - from_byproducts;
+
+        let limit = self.state.material_limit(id).unwrap_or(Count::MAX);
+        let cost = if m.count().saturating_add(missing) > limit {
+            MaterialCost::OverLimit
+        } else {
+            match self.state.material_cost(id, missing) {
+                Some(cost) if cost.is_finite() => MaterialCost::Known(cost),
+                _ => MaterialCost::Unknown,
+            }
+        };
+
+        MaterialRequirement {
+            material: id,
+            required,
+            available: m.count(),
+            from_byproducts,
+            missing,
+            currency: m.currency(),
+            cost,
+        }
+    }
+}
+
+impl State {
+    // Plans building `cnt` amount of a product by walking its whole
+    // dependency graph with the active perks applied.
+    // Intermediate products are first taken from the stock, then from the
+    // byproducts of the other planned constructions and then built,
+    // while the planned product itself is always built.
+    pub fn plan(&self, id: ProductId, cnt: Count) -> Plan {
+        let mut planner = Planner::new(self);
+        let builds = planner.produce(id, cnt);
+
+        let mut material_ids: Vec<_> = planner.consumed.keys().copied().collect();
+        material_ids.extend(planner.present.keys().copied());
+        material_ids.sort_unstable();
+        material_ids.dedup();
+
+        let materials: Vec<_> = material_ids
+            .into_iter()
+            .map(|id| planner.material_requirement(id))
+            .collect();
+
+        let products = planner
+            .required
+            .iter()
+            .map(|(id, required)| ProductRequirement {
+                product: *id,
+                required: *required,
+                from_stock: planner.from_stock.get(id).copied().unwrap_or(0),
+                from_byproducts: planner.from_byproducts.get(id).copied().unwrap_or(0),
+                builds: planner.builds.get(id).copied().unwrap_or(0),
+            })
+            .collect();
+
+        let cost_in = |currency| -> Price {
+            materials
+                .iter()
+                .filter(|m| m.currency == currency)
+                .map(|m| match m.cost {
+                    MaterialCost::Known(cost) => cost,
+                    MaterialCost::OverLimit => 0.0,
+                    MaterialCost::Unknown => Price::INFINITY,
+                })
+                .sum()
+        };
+
+        let missing_cost = cost_in(None);
+        let needed_money = (planner.money + missing_cost).max(planner.money_present);
+
+        let mut currency_ids: Vec<_> = planner.currencies.keys().copied().collect();
+        currency_ids.extend(planner.currencies_present.keys().copied());
+        currency_ids.extend(materials.iter().filter_map(|m| m.currency));
+        currency_ids.sort_unstable();
+        currency_ids.dedup();
+
+        let currencies = currency_ids
+            .into_iter()
+            .map(|id| {
+                let consumed = planner.currencies.get(&id).copied().unwrap_or(0.0);
+                let present = planner.currencies_present.get(&id).copied().unwrap_or(0.0);
+                let required = (consumed + cost_in(Some(id))).max(present);
+                let available = self.balance(Some(id));
+
+                CurrencyRequirement {
+                    currency: id,
+                    required,
+                    available,
+                    missing: (required - available).max(0.0),
+                }
+            })
+            .collect();
+
+        Plan {
+            product: id,
+            count: cnt,
+            builds,
+            products,
+            materials,
+            money: planner.money,
+            currencies,
+            missing_cost,
+            missing_money: (needed_money - self.money()).max(0.0),
+            unbuildable: planner.unbuildable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use either::Either;
+
+    use super::MaterialCost;
+    use crate::incremental::{
+        Objective, Product, ProductMaterial, Quantity, Relation, RelationKind, State,
+    };
+    use crate::types::Price;
+
+    #[test]
+    fn plan_intermediate_products() {
+        let s = State::new(
+            1.0,
+            Objective::default(),
+            vec![
+                ProductMaterial::new(1, None, "shop".to_string(), 1.0, Either::Left(1.0), true),
+                ProductMaterial::new(3, None, "lemon".to_string(), 1.0, Either::Left(1.0), true),
+            ],
+            vec![
+                Product::new(
+                    "juice".to_string(),
+                    None,
+                    vec![Relation::consumes(Quantity::Material(1, 2))],
+                    vec![],
+                    vec![],
+                    true,
+                ),
+                Product::new(
+                    "lemonade".to_string(),
+                    Some(1.0),
+                    vec![
+                        Relation::new(RelationKind::ManufacturedBy, Quantity::Material(0, 1)),
+                        Relation::consumes(Quantity::Product(0, 1, None)),
+                        Relation::consumes(Quantity::Money(0.5)),
+                    ],
+                    vec![],
+                    vec![],
+                    true,
+                ),
+            ],
+            vec![],
+            vec![],
+            vec![],
+        );
+
+        let plan = s.plan(1, 4);
+        assert!(plan.feasible());
+        assert!(!plan.ready());
+        assert_eq!(plan.builds(), 4);
+        assert_eq!(plan.money(), 2.0);
+        assert_eq!(plan.products()[0].builds(), 4);
+        assert_eq!(plan.materials()[0].missing(), 0);
+        assert_eq!(plan.materials()[1].required(), 8);
+        assert_eq!(plan.materials()[1].missing(), 5);
+        assert_eq!(plan.missing_cost(), 5.0);
+        assert_eq!(plan.missing_money(), 6.0);
+    }
+
+    #[test]
+    fn plan_byproducts() {
+        let s = State::new(
+            0.0,
+            Objective::default(),
+            vec![
+                ProductMaterial::new(0, None, "lemon".to_string(), 1.0, Either::Left(1.0), true),
+                ProductMaterial::new(0, None, "peel".to_string(), 1.0, Either::Left(1.0), true),
+            ],
+            vec![
+                Product::new(
+                    "juice".to_string(),
+                    None,
+                    vec![Relation::consumes(Quantity::Material(0, 1))],
+                    vec![],
+                    vec![],
+                    true,
+                )
+                .with_outputs(vec![Quantity::Material(1, 1)]),
+                Product::new(
+                    "candy".to_string(),
+                    Some(1.0),
+                    vec![
+                        Relation::consumes(Quantity::Product(0, 1, None)),
+                        Relation::consumes(Quantity::Material(1, 1)),
+                    ],
+                    vec![],
+                    vec![],
+                    true,
+                ),
+            ],
+            vec![],
+            vec![],
+            vec![],
+        );
+
+        let plan = s.plan(1, 3);
+        assert_eq!(plan.materials()[0].missing(), 3);
+        assert_eq!(plan.materials()[1].from_byproducts(), 3);
+        assert_eq!(plan.materials()[1].missing(), 0);
+        assert_eq!(plan.missing_cost(), 3.0);
+    }
+
+    #[test]
+    fn plan_many_materials() {
+        let s = State::new(
+            0.0,
+            Objective::default(),
+            vec![
+                ProductMaterial::new(0, None, "lemon".to_string(), 1.0, Either::Left(1.0), true),
+                ProductMaterial::new(
+                    0,
+                    None,
+                    "sugar".to_string(),
+                    1.0,
+                    Either::Right("x + 1".to_string()),
+                    true,
+                ),
+            ],
+            vec![
+                Product::new(
+                    "juice".to_string(),
+                    Some(1.0),
+                    vec![Relation::consumes(Quantity::Material(0, 1))],
+                    vec![],
+                    vec![],
+                    true,
+                ),
+                Product::new(
+                    "candy".to_string(),
+                    Some(1.0),
+                    vec![Relation::consumes(Quantity::Material(1, 1))],
+                    vec![],
+                    vec![],
+                    true,
+                ),
+            ],
+            vec![],
+            vec![],
+            vec![],
+        );
+
+        let plan = s.plan(0, 1_000_000_000);
+        assert!(plan.feasible());
+        assert_eq!(plan.missing_cost(), 1e9);
+
+        let plan = s.plan(1, 1_000_000_000);
+        assert!(plan.feasible());
+        assert!(!plan.ready());
+        assert_eq!(plan.materials()[0].cost(), MaterialCost::Unknown);
+        assert_eq!(plan.missing_money(), Price::INFINITY);
+    }
+}
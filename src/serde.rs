@@ -1,9 +1,14 @@
 use std::time::Duration;
 
 use either::Either;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, EnumAccess, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::prelude::{Count, Expr, Price, ProductMaterial, Timer};
+use crate::prelude::{
+    AutomationId, Comparison, Condition, Count, CurrencyId, Decay, Expr, PerkTarget, Price,
+    ProductConditionKind, ProductId, ProductMaterial, ProductMaterialId, Quantity, Stat, Timer,
+    DEFAULT_REFUND,
+};
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct ProductMaterialDef {
@@ -13,19 +18,47 @@ pub(crate) struct ProductMaterialDef {
     base_price: Price,
     #[serde(with = "either::serde_untagged")]
     growth: Either<f64, Expr>,
+    #[serde(default = "default_refund", with = "either::serde_untagged")]
+    refund: Either<f64, Expr>,
+    #[serde(default)]
+    currency: Option<CurrencyId>,
+    #[serde(default)]
+    regeneration: f64,
+    #[serde(default)]
+    decay: Option<Decay>,
+    #[serde(default)]
+    upkeep: Price,
     unlocked: bool,
 }
 
+fn default_refund() -> Either<f64, Expr> {
+    Either::Left(DEFAULT_REFUND)
+}
+
 impl From<ProductMaterialDef> for ProductMaterial {
     fn from(product: ProductMaterialDef) -> Self {
-        Self::new(
+        let material = Self::new(
             product.init_bought,
             product.limit,
             product.kind,
             product.base_price,
             product.growth,
             product.unlocked,
-        )
+        );
+
+        let material = material
+            .with_refund(product.refund)
+            .with_regeneration(product.regeneration)
+            .with_upkeep(product.upkeep);
+        let material = match product.decay {
+            Some(decay) => material.with_decay(decay),
+            None => material,
+        };
+
+        match product.currency {
+            Some(currency) => material.with_currency(currency),
+            None => material,
+        }
     }
 }
 
@@ -37,6 +70,11 @@ impl Into<ProductMaterialDef> for ProductMaterial {
             kind: self.name().to_string(),
             base_price: self.base_price,
             growth: self.growth,
+            refund: self.refund,
+            currency: self.currency,
+            regeneration: self.regeneration,
+            decay: self.decay,
+            upkeep: self.upkeep,
             unlocked: self.active,
         }
     }
@@ -56,3 +94,137 @@ impl Into<TimerDef> for Timer {
         TimerDef(self.duration().as_millis() as f64 / 1000.0)
     }
 }
+
+// Quantities are written directly instead of nested in a `Quantity` variant.
+#[derive(Deserialize, Serialize)]
+pub(crate) enum PerkTargetDef {
+    Money(Price),
+    Currency(CurrencyId, Price),
+    Material(ProductMaterialId, Count),
+    Product(
+        ProductId,
+        Count,
+        #[serde(default)] Option<ProductConditionKind>,
+    ),
+    CritChance(f64),
+    Regeneration(ProductMaterialId, f64),
+    MaterialDecay(ProductMaterialId, f64),
+    ProductDecay(ProductId, f64),
+    SellPrice(f64),
+    Interest(f64),
+    MaterialLimit(ProductMaterialId, f64),
+    MaterialGrowth(ProductMaterialId, f64),
+    AutomationTimer(AutomationId, f64),
+    AutomationBatch(AutomationId, f64),
+}
+
+impl From<PerkTargetDef> for PerkTarget {
+    fn from(target: PerkTargetDef) -> Self {
+        match target {
+            PerkTargetDef::Money(money) => Quantity::Money(money).into(),
+            PerkTargetDef::Currency(id, amount) => Quantity::Currency(id, amount).into(),
+            PerkTargetDef::Material(id, cnt) => Quantity::Material(id, cnt).into(),
+            PerkTargetDef::Product(id, cnt, kind) => Quantity::Product(id, cnt, kind).into(),
+            PerkTargetDef::CritChance(chance) => PerkTarget::CritChance(chance),
+            PerkTargetDef::Regeneration(id, rate) => PerkTarget::Regeneration(id, rate),
+            PerkTargetDef::MaterialDecay(id, by) => PerkTarget::MaterialDecay(id, by),
+            PerkTargetDef::ProductDecay(id, by) => PerkTarget::ProductDecay(id, by),
+            PerkTargetDef::SellPrice(by) => PerkTarget::SellPrice(by),
+            PerkTargetDef::Interest(by) => PerkTarget::Interest(by),
+            PerkTargetDef::MaterialLimit(id, by) => PerkTarget::MaterialLimit(id, by),
+            PerkTargetDef::MaterialGrowth(id, by) => PerkTarget::MaterialGrowth(id, by),
+            PerkTargetDef::AutomationTimer(id, by) => PerkTarget::AutomationTimer(id, by),
+            PerkTargetDef::AutomationBatch(id, by) => PerkTarget::AutomationBatch(id, by),
+        }
+    }
+}
+
+impl From<PerkTarget> for PerkTargetDef {
+    fn from(target: PerkTarget) -> Self {
+        match target {
+            PerkTarget::Quantity(Quantity::Money(money)) => PerkTargetDef::Money(money),
+            PerkTarget::Quantity(Quantity::Currency(id, amount)) => {
+                PerkTargetDef::Currency(id, amount)
+            }
+            PerkTarget::Quantity(Quantity::Material(id, cnt)) => PerkTargetDef::Material(id, cnt),
+            PerkTarget::Quantity(Quantity::Product(id, cnt, kind)) => {
+                PerkTargetDef::Product(id, cnt, kind)
+            }
+            PerkTarget::CritChance(chance) => PerkTargetDef::CritChance(chance),
+            PerkTarget::Regeneration(id, rate) => PerkTargetDef::Regeneration(id, rate),
+            PerkTarget::MaterialDecay(id, by) => PerkTargetDef::MaterialDecay(id, by),
+            PerkTarget::ProductDecay(id, by) => PerkTargetDef::ProductDecay(id, by),
+            PerkTarget::SellPrice(by) => PerkTargetDef::SellPrice(by),
+            PerkTarget::Interest(by) => PerkTargetDef::Interest(by),
+            PerkTarget::MaterialLimit(id, by) => PerkTargetDef::MaterialLimit(id, by),
+            PerkTarget::MaterialGrowth(id, by) => PerkTargetDef::MaterialGrowth(id, by),
+            PerkTarget::AutomationTimer(id, by) => PerkTargetDef::AutomationTimer(id, by),
+            PerkTarget::AutomationBatch(id, by) => PerkTargetDef::AutomationBatch(id, by),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum ConditionDef {
+    Money(Price),
+    Currency(CurrencyId, Price),
+    Material(ProductMaterialId, Count),
+    Product(
+        ProductId,
+        Count,
+        #[serde(default)] Option<ProductConditionKind>,
+    ),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Vec<Condition>),
+    Compare(Stat, Comparison, f64),
+    Expr(Expr),
+}
+
+impl From<ConditionDef> for Condition {
+    fn from(cond: ConditionDef) -> Self {
+        match cond {
+            ConditionDef::Money(money) => Condition::Money(money),
+            ConditionDef::Currency(id, amount) => Condition::Currency(id, amount),
+            ConditionDef::Material(id, cnt) => Condition::Material(id, cnt),
+            ConditionDef::Product(id, cnt, kind) => Condition::Product(id, cnt, kind),
+            ConditionDef::All(conds) => Condition::All(conds),
+            ConditionDef::Any(conds) => Condition::Any(conds),
+            ConditionDef::Not(conds) => Condition::Not(conds),
+            ConditionDef::Compare(stat, cmp, value) => Condition::Compare(stat, cmp, value),
+            ConditionDef::Expr(expr) => Condition::Expr(expr),
+        }
+    }
+}
+
+// A condition is either written as an expression string
+// or as one of the tagged variants of [`Condition`].
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ConditionVisitor;
+
+        impl<'de> Visitor<'de> for ConditionVisitor {
+            type Value = Condition;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a condition or a condition expression")
+            }
+
+            fn visit_str<E: de::Error>(self, expr: &str) -> Result<Condition, E> {
+                Ok(Condition::Expr(expr.to_string()))
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Condition, A::Error> {
+                ConditionDef::deserialize(de::value::EnumAccessDeserializer::new(data))
+                    .map(Condition::from)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Condition, A::Error> {
+                ConditionDef::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(Condition::from)
+            }
+        }
+
+        deserializer.deserialize_any(ConditionVisitor)
+    }
+}
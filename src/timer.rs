@@ -21,10 +21,20 @@ impl Timer {
     }
 
     pub fn tick(&mut self, delta: Duration) -> bool {
+        self.tick_over(delta, self.duration)
+    }
+
+    // Ticks the timer as if its duration was `duration`, f.e when perks change it.
+    pub fn tick_over(&mut self, delta: Duration, duration: Duration) -> bool {
         self.elapsed += delta;
-        if self.elapsed >= self.duration {
-            let times = self.elapsed.as_nanos() / self.duration.as_nanos();
-            self.elapsed -= times as u32 * self.duration;
+        if duration.is_zero() {
+            self.elapsed = Duration::ZERO;
+            return true;
+        }
+
+        if self.elapsed >= duration {
+            let times = self.elapsed.as_nanos() / duration.as_nanos();
+            self.elapsed -= times as u32 * duration;
             true
         } else {
             false
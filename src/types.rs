@@ -6,3 +6,7 @@ pub type AutomationId = usize;
 pub type Count = u64;
 pub type Price = f64;
 pub type Expr = String;
+pub type CurrencyId = usize;
+pub type LoseConditionId = usize;
+pub type StageId = usize;
+pub type ChallengeId = usize;